@@ -1,16 +1,449 @@
-use std::path::PathBuf;
+use crate::core::color::parse_color;
+use crate::core::theme::{NamedTheme, Theme};
+use crate::error::{Mark2PdfError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Clone)]
+/// Standard paper sizes, or a custom width and height in mm.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "PageFormatValue", into = "PageFormatValue")]
+pub enum PageFormat {
+    A3,
+    A4,
+    A5,
+    Letter,
+    Legal,
+    Tabloid,
+    Custom { width: f32, height: f32 },
+}
+
+impl PageFormat {
+    // Portrait width and height in mm
+    pub fn dimensions(&self) -> (f32, f32) {
+        match *self {
+            PageFormat::A3 => (297.0, 420.0),
+            PageFormat::A4 => (210.0, 297.0),
+            PageFormat::A5 => (148.0, 210.0),
+            PageFormat::Letter => (215.9, 279.4),
+            PageFormat::Legal => (215.9, 355.6),
+            PageFormat::Tabloid => (279.4, 431.8),
+            PageFormat::Custom { width, height } => (width, height),
+        }
+    }
+}
+
+impl FromStr for PageFormat {
+    type Err = Mark2PdfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "a3" => Ok(PageFormat::A3),
+            "a4" => Ok(PageFormat::A4),
+            "a5" => Ok(PageFormat::A5),
+            "letter" => Ok(PageFormat::Letter),
+            "legal" => Ok(PageFormat::Legal),
+            "tabloid" => Ok(PageFormat::Tabloid),
+            other => Err(Mark2PdfError::ConfigError(format!(
+                "unknown page format '{}' (expected A3, A4, A5, Letter, Legal or Tabloid)",
+                other
+            ))),
+        }
+    }
+}
+
+// Config files name a paper size or give a `{ width, height }` table
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PageFormatValue {
+    Name(String),
+    Size { width: f32, height: f32 },
+}
+
+impl TryFrom<PageFormatValue> for PageFormat {
+    type Error = Mark2PdfError;
+
+    fn try_from(value: PageFormatValue) -> Result<Self> {
+        match value {
+            PageFormatValue::Name(name) => name.parse(),
+            PageFormatValue::Size { width, height } => Ok(PageFormat::Custom { width, height }),
+        }
+    }
+}
+
+impl From<PageFormat> for PageFormatValue {
+    fn from(format: PageFormat) -> Self {
+        let name = match format {
+            PageFormat::A3 => "A3",
+            PageFormat::A4 => "A4",
+            PageFormat::A5 => "A5",
+            PageFormat::Letter => "Letter",
+            PageFormat::Legal => "Legal",
+            PageFormat::Tabloid => "Tabloid",
+            PageFormat::Custom { width, height } => return PageFormatValue::Size { width, height },
+        };
+        PageFormatValue::Name(name.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Orientation {
+    Portrait,
+    Landscape,
+}
+
+impl FromStr for Orientation {
+    type Err = Mark2PdfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "portrait" => Ok(Orientation::Portrait),
+            "landscape" => Ok(Orientation::Landscape),
+            other => Err(Mark2PdfError::ConfigError(format!(
+                "unknown orientation '{}' (expected portrait or landscape)",
+                other
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for Orientation {
+    type Error = Mark2PdfError;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Orientation> for String {
+    fn from(orientation: Orientation) -> Self {
+        match orientation {
+            Orientation::Portrait => "portrait".to_string(),
+            Orientation::Landscape => "landscape".to_string(),
+        }
+    }
+}
+
+/// Page margins in mm.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "MarginsValue", into = "String")]
+pub struct Margins {
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub left: f32,
+}
+
+impl Margins {
+    pub fn uniform(margin: f32) -> Self {
+        Self {
+            top: margin,
+            right: margin,
+            bottom: margin,
+            left: margin,
+        }
+    }
+}
+
+impl FromStr for Margins {
+    type Err = Mark2PdfError;
+
+    /// Parses the CSS `margin` shorthand: one to four lengths in
+    /// top, right, bottom, left order, e.g. `"1in 20mm 2cm"`.
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s.split_whitespace().map(parse_length).collect::<Result<Vec<f32>>>()?;
+        let (top, right, bottom, left) = match values[..] {
+            [all] => (all, all, all, all),
+            [vertical, horizontal] => (vertical, horizontal, vertical, horizontal),
+            [top, horizontal, bottom] => (top, horizontal, bottom, horizontal),
+            [top, right, bottom, left] => (top, right, bottom, left),
+            _ => {
+                return Err(Mark2PdfError::ConfigError(format!(
+                    "margin '{}' must have one to four lengths",
+                    s
+                )))
+            }
+        };
+        Ok(Self { top, right, bottom, left })
+    }
+}
+
+// A number of millimetres or a CSS margin shorthand
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MarginsValue {
+    Mm(f32),
+    Css(String),
+}
+
+impl TryFrom<MarginsValue> for Margins {
+    type Error = Mark2PdfError;
+
+    fn try_from(value: MarginsValue) -> Result<Self> {
+        match value {
+            MarginsValue::Mm(mm) => Ok(Margins::uniform(mm)),
+            MarginsValue::Css(margins) => margins.parse(),
+        }
+    }
+}
+
+impl fmt::Display for Margins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}mm {}mm {}mm {}mm", self.top, self.right, self.bottom, self.left)
+    }
+}
+
+impl From<Margins> for String {
+    fn from(margins: Margins) -> Self {
+        margins.to_string()
+    }
+}
+
+/// Converts a length such as `20mm`, `2cm`, `1in`, `72pt` or `96px` to mm.
+/// Unitless values are only accepted for zero.
+pub fn parse_length(value: &str) -> Result<f32> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+')))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f32 = number
+        .parse()
+        .map_err(|_| Mark2PdfError::ConfigError(format!("invalid length '{}'", value)))?;
+    let mm = match unit.to_ascii_lowercase().as_str() {
+        "mm" => number,
+        "cm" => number * 10.0,
+        "in" => number * 25.4,
+        "pt" => number * 25.4 / 72.0,
+        "px" => number * 25.4 / 96.0,
+        "" if number == 0.0 => 0.0,
+        _ => {
+            return Err(Mark2PdfError::ConfigError(format!(
+                "invalid length '{}' (expected a unit of mm, cm, in, pt or px)",
+                value
+            )))
+        }
+    };
+    Ok(mm)
+}
+
+/// 1-based page numbers, e.g. `"1"`, `"2-4, 7"` or `"3-"` for page 3 onwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PageRanges(Vec<(usize, Option<usize>)>);
+
+impl PageRanges {
+    pub fn contains(&self, page: usize) -> bool {
+        self.0.iter().any(|&(start, end)| page >= start && end.is_none_or(|end| page <= end))
+    }
+}
+
+impl FromStr for PageRanges {
+    type Err = Mark2PdfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Mark2PdfError::ConfigError(format!("invalid page range '{}'", s));
+        let number = |n: &str| n.trim().parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(invalid);
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            let range = match part.split_once('-') {
+                Some((start, end)) if end.trim().is_empty() => (number(start)?, None),
+                Some((start, end)) => (number(start)?, Some(number(end)?)),
+                None => (number(part)?, Some(number(part)?)),
+            };
+            if range.1.is_some_and(|end| end < range.0) {
+                return Err(invalid());
+            }
+            ranges.push(range);
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl TryFrom<String> for PageRanges {
+    type Error = Mark2PdfError;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<PageRanges> for String {
+    fn from(ranges: PageRanges) -> Self {
+        let parts: Vec<String> = ranges
+            .0
+            .iter()
+            .map(|&(start, end)| match end {
+                Some(end) if end == start => start.to_string(),
+                Some(end) => format!("{}-{}", start, end),
+                None => format!("{}-", start),
+            })
+            .collect();
+        parts.join(", ")
+    }
+}
+
+// Written as a `text` or `image` key next to the other watermark settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatermarkContent {
+    Text(String),
+    Image(PathBuf),
+}
+
+/// A stamp such as "DRAFT" drawn across the middle of each page.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "WatermarkValue")]
+pub struct Watermark {
+    #[serde(flatten)]
+    pub content: WatermarkContent,
+    // Degrees counter-clockwise
+    pub angle: f32,
+    // Font size in points for text, width in mm for images
+    pub size: f32,
+    // CSS colour of text watermarks
+    pub color: String,
+    pub opacity: f32,
+    // Every page when unset
+    pub pages: Option<PageRanges>,
+    // Drawn over the content rather than beneath it
+    pub above_content: bool,
+}
+
+impl Watermark {
+    pub fn text<S: Into<String>>(text: S) -> Self {
+        Self::with_content(WatermarkContent::Text(text.into()), 72.0)
+    }
+
+    pub fn image<P: Into<PathBuf>>(path: P) -> Self {
+        Self::with_content(WatermarkContent::Image(path.into()), 100.0)
+    }
+
+    fn with_content(content: WatermarkContent, size: f32) -> Self {
+        Self {
+            content,
+            angle: 45.0,
+            size,
+            color: "gray".to_string(),
+            opacity: 0.2,
+            pages: None,
+            above_content: false,
+        }
+    }
+
+    pub fn with_angle(mut self, degrees: f32) -> Self {
+        self.angle = degrees;
+        self
+    }
+
+    pub fn with_size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_color<S: Into<String>>(mut self, color: S) -> Self {
+        self.color = color.into();
+        self
+    }
+
+    pub fn with_opacity(mut self, opacity: f32) -> Self {
+        self.opacity = opacity;
+        self
+    }
+
+    pub fn with_pages(mut self, pages: PageRanges) -> Self {
+        self.pages = Some(pages);
+        self
+    }
+
+    pub fn with_above_content(mut self, above: bool) -> Self {
+        self.above_content = above;
+        self
+    }
+
+    pub fn applies_to(&self, page: usize) -> bool {
+        self.pages.as_ref().is_none_or(|pages| pages.contains(page))
+    }
+}
+
+// Watermark settings left out of a config file keep the defaults for its content
+#[derive(Deserialize)]
+struct WatermarkValue {
+    #[serde(flatten)]
+    content: WatermarkContent,
+    angle: Option<f32>,
+    size: Option<f32>,
+    color: Option<String>,
+    opacity: Option<f32>,
+    pages: Option<PageRanges>,
+    above_content: Option<bool>,
+}
+
+impl From<WatermarkValue> for Watermark {
+    fn from(value: WatermarkValue) -> Self {
+        let mut watermark = match value.content {
+            WatermarkContent::Text(text) => Watermark::text(text),
+            WatermarkContent::Image(path) => Watermark::image(path),
+        };
+        watermark.angle = value.angle.unwrap_or(watermark.angle);
+        watermark.size = value.size.unwrap_or(watermark.size);
+        watermark.color = value.color.unwrap_or(watermark.color);
+        watermark.opacity = value.opacity.unwrap_or(watermark.opacity);
+        watermark.pages = value.pages;
+        watermark.above_content = value.above_content.unwrap_or(watermark.above_content);
+        watermark
+    }
+}
+
+/// Conversion settings. Config files and front matter use the field names
+/// as keys; see [`crate::config_file`].
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub input_file: Option<PathBuf>,
     pub output_file: Option<PathBuf>,
-    pub page_width: f32,
-    pub page_height: f32,
-    pub margin: f32,
+    pub page_format: PageFormat,
+    pub orientation: Orientation,
+    #[serde(alias = "margin")]
+    pub margins: Margins,
     pub font_size: f32,
     pub enable_gfm: bool,
     pub enable_syntax_highlighting: bool,
     pub enable_math: bool,
+    pub fallback_fonts: Vec<PathBuf>,
+    pub font_family: Option<String>,
+    pub font_dirs: Vec<PathBuf>,
+    pub emoji_font: Option<PathBuf>,
+    pub emoji_images: Option<PathBuf>,
+    pub theme: NamedTheme,
+    pub custom_theme: Option<Theme>,
+    #[serde(alias = "stylesheet")]
+    pub stylesheets: Vec<PathBuf>,
+    pub custom_css: Option<String>,
+    pub page_background: Option<String>,
+    pub page_background_image: Option<PathBuf>,
+    pub print_background: bool,
+    pub watermark: Option<Watermark>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Vec<String>,
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub variables: BTreeMap<String, String>,
+    // Whether `Mark2Pdf::convert` looks for config files
+    #[serde(skip)]
+    pub config_files: bool,
+    // Whether `Mark2Pdf::convert` applies `MARK2PDF_*` environment variables
+    #[serde(skip)]
+    pub environment: bool,
+    // Settings given through the `with_*` methods, which override front
+    // matter and config files even when they equal the defaults
+    #[serde(skip)]
+    explicit: BTreeSet<&'static str>,
 }
 
 impl Config {
@@ -18,60 +451,447 @@ impl Config {
         Self {
             input_file: None,
             output_file: None,
-            page_width: 210.0, // A4 width in mm
-            page_height: 297.0, // A4 height in mm
-            margin: 20.0,      // Default margin in mm
-            font_size: 12.0,   // Default font size in points
+            page_format: PageFormat::A4,
+            orientation: Orientation::Portrait,
+            margins: Margins::uniform(20.0), // Default margin in mm
+            font_size: 11.0,   // Default font size in points
             enable_gfm: true,
             enable_syntax_highlighting: true,
             enable_math: false,
+            fallback_fonts: Vec::new(),
+            font_family: None,
+            font_dirs: Vec::new(),
+            emoji_font: None,
+            emoji_images: None,
+            theme: NamedTheme::Default,
+            custom_theme: None,
+            stylesheets: Vec::new(),
+            custom_css: None,
+            page_background: None,
+            page_background_image: None,
+            print_background: true,
+            watermark: None,
+            title: None,
+            author: None,
+            subject: None,
+            keywords: Vec::new(),
+            header: None,
+            footer: None,
+            variables: BTreeMap::new(),
+            config_files: true,
+            environment: false,
+            explicit: BTreeSet::new(),
         }
     }
 
     pub fn with_input_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
         self.input_file = Some(path.into());
-        self
+        self.set("input_file")
     }
 
     pub fn with_output_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
         self.output_file = Some(path.into());
-        self
+        self.set("output_file")
     }
 
     pub fn with_page_size(mut self, width: f32, height: f32) -> Self {
-        self.page_width = width;
-        self.page_height = height;
-        self
+        self.page_format = PageFormat::Custom { width, height };
+        self.set("page_format")
+    }
+
+    pub fn with_page_format(mut self, format: PageFormat) -> Self {
+        self.page_format = format;
+        self.set("page_format")
+    }
+
+    pub fn with_orientation(mut self, orientation: Orientation) -> Self {
+        self.orientation = orientation;
+        self.set("orientation")
+    }
+
+    /// Checks for settings no document can be laid out with.
+    pub fn validate(&self) -> Result<()> {
+        let (width, height) = self.page_size();
+        if !(width > 0.0 && height > 0.0) {
+            return Err(Mark2PdfError::ConfigError(format!(
+                "page size {}mm x {}mm must be positive",
+                width, height
+            )));
+        }
+        let margins = self.margins;
+        if [margins.top, margins.right, margins.bottom, margins.left].iter().any(|m| *m < 0.0) {
+            return Err(Mark2PdfError::ConfigError("margins must not be negative".to_string()));
+        }
+        if margins.left + margins.right >= width {
+            return Err(Mark2PdfError::ConfigError(format!(
+                "left and right margins ({}mm + {}mm) leave no room on a {}mm wide page",
+                margins.left, margins.right, width
+            )));
+        }
+        if margins.top + margins.bottom >= height {
+            return Err(Mark2PdfError::ConfigError(format!(
+                "top and bottom margins ({}mm + {}mm) leave no room on a {}mm high page",
+                margins.top, margins.bottom, height
+            )));
+        }
+        if !(self.font_size > 0.0 && self.font_size.is_finite()) {
+            return Err(Mark2PdfError::ConfigError(format!(
+                "font size {}pt must be positive",
+                self.font_size
+            )));
+        }
+        if let Some(color) = &self.page_background {
+            if parse_color(color).is_none() {
+                return Err(Mark2PdfError::ConfigError(format!("invalid page background colour '{}'", color)));
+            }
+        }
+        if let Some(watermark) = &self.watermark {
+            if parse_color(&watermark.color).is_none() {
+                return Err(Mark2PdfError::ConfigError(format!("invalid watermark colour '{}'", watermark.color)));
+            }
+            if !(watermark.size > 0.0 && watermark.size.is_finite()) {
+                return Err(Mark2PdfError::ConfigError(format!("watermark size {} must be positive", watermark.size)));
+            }
+            if !(0.0..=1.0).contains(&watermark.opacity) {
+                return Err(Mark2PdfError::ConfigError(format!("watermark opacity {} must be between 0 and 1", watermark.opacity)));
+            }
+        }
+        Ok(())
+    }
+
+    // Page width and height in mm. The orientation turns named formats; custom sizes are used as given
+    pub fn page_size(&self) -> (f32, f32) {
+        let (width, height) = self.page_format.dimensions();
+        match (self.page_format, self.orientation) {
+            (PageFormat::Custom { .. }, _) => (width, height),
+            (_, Orientation::Portrait) => (width.min(height), width.max(height)),
+            (_, Orientation::Landscape) => (width.max(height), width.min(height)),
+        }
+    }
+
+    #[deprecated(note = "use `page_size()`, or `page_format` and `orientation`")]
+    pub fn page_width(&self) -> f32 {
+        self.page_size().0
+    }
+
+    #[deprecated(note = "use `page_size()`, or `page_format` and `orientation`")]
+    pub fn page_height(&self) -> f32 {
+        self.page_size().1
     }
 
     pub fn with_margin(mut self, margin: f32) -> Self {
-        self.margin = margin;
-        self
+        self.margins = Margins::uniform(margin);
+        self.set("margins")
+    }
+
+    pub fn with_margins(mut self, margins: Margins) -> Self {
+        self.margins = margins;
+        self.set("margins")
     }
 
     pub fn with_font_size(mut self, size: f32) -> Self {
         self.font_size = size;
-        self
+        self.set("font_size")
     }
 
     pub fn with_gfm(mut self, enable: bool) -> Self {
         self.enable_gfm = enable;
-        self
+        self.set("enable_gfm")
     }
 
+    // Not implemented yet; code blocks are drawn without highlighting either way
     pub fn with_syntax_highlighting(mut self, enable: bool) -> Self {
         self.enable_syntax_highlighting = enable;
-        self
+        self.set("enable_syntax_highlighting")
     }
 
+    // Not implemented yet; enabling it only logs a warning and math stays plain text
     pub fn with_math(mut self, enable: bool) -> Self {
         self.enable_math = enable;
+        self.set("enable_math")
+    }
+
+    // Fonts tried in order after the builtin Helvetica when a character has no glyph
+    pub fn with_fallback_font<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.fallback_fonts.push(path.into());
+        self.set("fallback_fonts")
+    }
+
+    // CSS-style family list such as `"Noto Serif", serif`, looked up in the installed fonts
+    pub fn with_font_family<S: Into<String>>(mut self, family: S) -> Self {
+        self.font_family = Some(family.into());
+        self.set("font_family")
+    }
+
+    // Searched in addition to the standard system and user font directories
+    pub fn with_font_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.font_dirs.push(dir.into());
+        self.set("font_dirs")
+    }
+
+    // Colour bitmap (CBDT/sbix) font for emoji; defaults to an installed Noto Color Emoji
+    pub fn with_emoji_font<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.emoji_font = Some(path.into());
+        self.set("emoji_font")
+    }
+
+    // Directory of PNG emoji sprites named by codepoint (`1f680.png`), used instead of a font
+    pub fn with_emoji_images<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.emoji_images = Some(dir.into());
+        self.set("emoji_images")
+    }
+
+    // Bundled stylesheet and fonts applied before any user stylesheets
+    pub fn with_theme(mut self, theme: NamedTheme) -> Self {
+        self.theme = theme;
+        self.set("theme")
+    }
+
+    // Replaces the values of the built-in stylesheet; named themes and user CSS still apply on top
+    pub fn with_custom_theme(mut self, theme: Theme) -> Self {
+        self.custom_theme = Some(theme);
+        self.set("custom_theme")
+    }
+
+    // Applied after the built-in stylesheet, in the order they are added. Relative `url()`s, such as
+    // background images, are found next to the stylesheet
+    pub fn with_stylesheet<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.stylesheets.push(path.into());
+        self.set("stylesheets")
+    }
+
+    // Raw CSS applied after all stylesheet files
+    pub fn with_custom_css<S: Into<String>>(mut self, css: S) -> Self {
+        self.custom_css = Some(css.into());
+        self.set("custom_css")
+    }
+
+    // CSS colour filling every page edge to edge, overriding an html/body background
+    pub fn with_page_background<S: Into<String>>(mut self, color: S) -> Self {
+        self.page_background = Some(color.into());
+        self.set("page_background")
+    }
+
+    // Image scaled to cover every page, drawn above the page background colour
+    pub fn with_page_background_image<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.page_background_image = Some(path.into());
+        self.set("page_background_image")
+    }
+
+    // When false, CSS background colours are dropped; the page background settings above still apply
+    pub fn with_print_background(mut self, enable: bool) -> Self {
+        self.print_background = enable;
+        self.set("print_background")
+    }
+
+    // Text or image stamped across the middle of the selected pages
+    pub fn with_watermark(mut self, watermark: Watermark) -> Self {
+        self.watermark = Some(watermark);
+        self.set("watermark")
+    }
+
+    // Document information shown by PDF viewers
+    pub fn with_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self.set("title")
+    }
+
+    pub fn with_author<S: Into<String>>(mut self, author: S) -> Self {
+        self.author = Some(author.into());
+        self.set("author")
+    }
+
+    pub fn with_subject<S: Into<String>>(mut self, subject: S) -> Self {
+        self.subject = Some(subject.into());
+        self.set("subject")
+    }
+
+    pub fn with_keyword<S: Into<String>>(mut self, keyword: S) -> Self {
+        self.keywords.push(keyword.into());
+        self.set("keywords")
+    }
+
+    // Text centred in the top margin; `{page}` and `{pages}` are replaced with page numbers
+    pub fn with_header<S: Into<String>>(mut self, header: S) -> Self {
+        self.header = Some(header.into());
+        self.set("header")
+    }
+
+    // Text centred in the bottom margin, with the same placeholders as the header
+    pub fn with_footer<S: Into<String>>(mut self, footer: S) -> Self {
+        self.footer = Some(footer.into());
+        self.set("footer")
+    }
+
+    // Value of `{{ name }}` in document bodies, overriding a front matter variable of the same name.
+    // `title`, `author` and `subject` default to the document information and `date` to today
+    pub fn with_variable<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.variables.insert(name.into(), value.into());
+        self.set("variables")
+    }
+
+    // Set to false to ignore mark2pdf.toml and .mark2pdfrc.json files
+    pub fn with_config_files(mut self, enable: bool) -> Self {
+        self.config_files = enable;
+        self
+    }
+
+    // Set to true to apply `MARK2PDF_*` environment variables above config files; see `config_env`
+    pub fn with_environment(mut self, enable: bool) -> Self {
+        self.environment = enable;
+        self
+    }
+
+    /// Applies the settings `overrides` was given through its `with_*`
+    /// methods, and any others it changes from their defaults.
+    pub fn merge(&mut self, overrides: &Config) -> Result<()> {
+        let defaults = to_json(&Config::default())?;
+        let mut changed = to_json(overrides)?;
+        if let serde_json::Value::Object(map) = &mut changed {
+            map.retain(|key, value| overrides.explicit.contains(key.as_str()) || defaults.get(key) != Some(value));
+        }
+        self.merge_value(changed)?;
+        self.explicit.extend(&overrides.explicit);
+        Ok(())
+    }
+
+    fn set(mut self, field: &'static str) -> Self {
+        self.explicit.insert(field);
         self
     }
+
+    /// Overlays a partial configuration, such as a parsed config file.
+    /// `variables` and `custom_theme` are merged key by key; other settings are replaced.
+    pub fn merge_value(&mut self, overlay: serde_json::Value) -> Result<()> {
+        let serde_json::Value::Object(overlay) = overlay else {
+            return Err(Mark2PdfError::ConfigError("settings must be a table of keys and values".to_string()));
+        };
+        let mut merged = to_json(self)?;
+        if let serde_json::Value::Object(merged) = &mut merged {
+            for (key, value) in overlay {
+                match merged.get_mut(&key) {
+                    Some(existing) if matches!(key.as_str(), "variables" | "custom_theme") => merge_json(existing, value),
+                    _ => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+        }
+        let (config_files, environment, explicit) = (self.config_files, self.environment, std::mem::take(&mut self.explicit));
+        *self = serde_json::from_value(merged).map_err(|e| Mark2PdfError::ConfigError(e.to_string()))?;
+        self.config_files = config_files;
+        self.environment = environment;
+        self.explicit = explicit;
+        Ok(())
+    }
+
+    // Makes relative file and directory paths relative to `base` instead
+    pub(crate) fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        };
+        let optional = [&mut self.input_file, &mut self.output_file, &mut self.emoji_font, &mut self.emoji_images, &mut self.page_background_image];
+        optional.into_iter().flatten().for_each(resolve);
+        self.fallback_fonts.iter_mut().chain(&mut self.font_dirs).chain(&mut self.stylesheets).for_each(resolve);
+        if let Some(Watermark { content: WatermarkContent::Image(path), .. }) = &mut self.watermark {
+            resolve(path);
+        }
+    }
+}
+
+fn to_json(config: &Config) -> Result<serde_json::Value> {
+    serde_json::to_value(config).map_err(|e| Mark2PdfError::ConfigError(e.to_string()))
+}
+
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self::new()
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_size_with_orientation() {
+        let config = Config::new().with_page_format("letter".parse().unwrap());
+        assert_eq!(config.page_size(), (215.9, 279.4));
+        let config = config.with_orientation(Orientation::Landscape);
+        assert_eq!(config.page_size(), (279.4, 215.9));
+        assert!("B5".parse::<PageFormat>().is_err());
+        assert_eq!(Config::new().with_page_size(297.0, 210.0).page_size(), (297.0, 210.0));
+    }
+
+    #[test]
+    fn test_parse_margins_with_units() {
+        let margins: Margins = "1in 20mm 2cm".parse().unwrap();
+        assert_eq!(margins, Margins { top: 25.4, right: 20.0, bottom: 20.0, left: 20.0 });
+        let margins: Margins = "72pt".parse().unwrap();
+        assert_eq!(margins, Margins::uniform(25.4));
+        assert_eq!(parse_length("96px").unwrap(), 25.4);
+        assert!("20".parse::<Margins>().is_err());
+        assert!("1in 2in 3in 4in 5in".parse::<Margins>().is_err());
+        assert!(matches!("abc".parse::<Margins>(), Err(Mark2PdfError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_validate_rejects_margins_wider_than_page() {
+        assert!(Config::new().validate().is_ok());
+        let config = Config::new().with_page_format(PageFormat::A5).with_margins("20mm 80mm".parse().unwrap());
+        assert!(matches!(config.validate(), Err(Mark2PdfError::ConfigError(_))));
+        assert!(Config::new().with_font_size(0.0).validate().is_err());
+        assert!(Config::new().with_page_background("#fdf6e3").validate().is_ok());
+        assert!(Config::new().with_page_background("not-a-colour").validate().is_err());
+        for opacity in [0.0, 0.15, 1.0] {
+            assert!(Config::new().with_watermark(Watermark::text("DRAFT").with_opacity(opacity)).validate().is_ok());
+        }
+        for opacity in [-0.5, 1.5, f32::NAN] {
+            assert!(Config::new().with_watermark(Watermark::text("DRAFT").with_opacity(opacity)).validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_explicit_defaults_override_merged_settings() {
+        let mut config = Config::new();
+        config.merge_value(serde_json::json!({ "theme": "dark", "font_size": 14, "print_background": false })).unwrap();
+        let explicit = Config::new().with_theme(NamedTheme::Default).with_font_size(11.0).with_print_background(true);
+        config.merge(&explicit).unwrap();
+        assert_eq!(config.theme, NamedTheme::Default);
+        assert_eq!(config.font_size, 11.0);
+        assert!(config.print_background);
+
+        // Settings left alone are kept
+        let mut config = Config::new().with_font_size(14.0);
+        config.merge(&Config::new().with_theme(NamedTheme::Dark)).unwrap();
+        assert_eq!((config.theme, config.font_size), (NamedTheme::Dark, 14.0));
+    }
+
+    #[test]
+    fn test_page_ranges() {
+        let ranges: PageRanges = "1, 3-4, 7-".parse().unwrap();
+        let pages: Vec<usize> = (1..=9).filter(|page| ranges.contains(*page)).collect();
+        assert_eq!(pages, vec![1, 3, 4, 7, 8, 9]);
+        assert!("0".parse::<PageRanges>().is_err());
+        assert!("4-2".parse::<PageRanges>().is_err());
+        assert!("".parse::<PageRanges>().is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Environment variable overrides. Every [`Config`] setting can be set with
+//! `MARK2PDF_` followed by its field name in capitals, such as
+//! `MARK2PDF_PAGE_FORMAT=Letter`, `MARK2PDF_MARGIN="1in 2cm"` or
+//! `MARK2PDF_ENABLE_GFM=false`. They override config files and are in turn
+//! overridden by front matter and explicit options. `Mark2Pdf` only reads
+//! them with [`Config::with_environment`], as the command line tool does.
+//!
+//! - Flags accept `true`/`false`, `yes`/`no`, `on`/`off` or `1`/`0`.
+//! - Lists of paths are separated like `PATH`; `MARK2PDF_KEYWORDS` by commas.
+//! - `MARK2PDF_WATERMARK` is the watermark text, or a JSON object with the
+//!   config file keys, as is `MARK2PDF_CUSTOM_THEME`.
+//! - `MARK2PDF_VAR_<NAME>` sets the template variable `name`. Names with
+//!   lower case letters keep their case, so `MARK2PDF_VAR_releaseDate` sets
+//!   `releaseDate`.
+//! - `MARK2PDF_CONFIG_FILES=false` turns off config file discovery.
+
+use crate::config::Config;
+use crate::error::{Mark2PdfError, Result};
+use serde_json::Value;
+use std::env;
+
+pub const PREFIX: &str = "MARK2PDF_";
+
+// How a variable's text becomes a setting
+#[derive(Clone, Copy)]
+enum Kind {
+    Text,
+    Number,
+    Flag,
+    // Millimetres or a CSS margin shorthand
+    Length,
+    Paths,
+    List,
+    Json,
+    // Name or a JSON `{ "width": .., "height": .. }` table
+    PageFormat,
+    Watermark,
+}
+
+const SETTINGS: [(&str, Kind); 28] = [
+    ("input_file", Kind::Text),
+    ("output_file", Kind::Text),
+    ("page_format", Kind::PageFormat),
+    ("orientation", Kind::Text),
+    ("margins", Kind::Length),
+    ("font_size", Kind::Number),
+    ("enable_gfm", Kind::Flag),
+    ("enable_syntax_highlighting", Kind::Flag),
+    ("enable_math", Kind::Flag),
+    ("fallback_fonts", Kind::Paths),
+    ("font_family", Kind::Text),
+    ("font_dirs", Kind::Paths),
+    ("emoji_font", Kind::Text),
+    ("emoji_images", Kind::Text),
+    ("theme", Kind::Text),
+    ("custom_theme", Kind::Json),
+    ("stylesheets", Kind::Paths),
+    ("custom_css", Kind::Text),
+    ("page_background", Kind::Text),
+    ("page_background_image", Kind::Text),
+    ("print_background", Kind::Flag),
+    ("watermark", Kind::Watermark),
+    ("title", Kind::Text),
+    ("author", Kind::Text),
+    ("subject", Kind::Text),
+    ("keywords", Kind::List),
+    ("header", Kind::Text),
+    ("footer", Kind::Text),
+];
+
+// The singular spellings `Config` also accepts
+const ALIASES: [(&str, &str); 2] = [("margin", "margins"), ("stylesheet", "stylesheets")];
+
+impl Config {
+    /// The defaults with any `MARK2PDF_*` environment variables applied.
+    pub fn from_env() -> Result<Config> {
+        let mut config = Config::default();
+        config.merge_env()?;
+        Ok(config)
+    }
+
+    pub fn merge_env(&mut self) -> Result<()> {
+        self.merge_vars(vars())
+    }
+
+    /// Applies `MARK2PDF_*` variables from `vars`; other names are skipped.
+    pub fn merge_vars<I, K, V>(&mut self, vars: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let (overlay, config_files) = overlay(vars)?;
+        if let Some(enable) = config_files {
+            self.config_files = enable;
+        }
+        self.merge_value(overlay)
+    }
+}
+
+// The process environment, without names or values that are not Unicode
+pub(crate) fn vars() -> impl Iterator<Item = (String, String)> {
+    env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+}
+
+// The settings in `vars` as a config overlay, and `MARK2PDF_CONFIG_FILES` if set
+pub(crate) fn overlay<I, K, V>(vars: I) -> Result<(Value, Option<bool>)>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut overlay = serde_json::Map::new();
+    let mut variables = serde_json::Map::new();
+    let mut config_files = None;
+    for (name, value) in vars {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        let Some(key) = name.strip_prefix(PREFIX) else { continue };
+        if let Some(variable) = key.strip_prefix("VAR_") {
+            let variable = match variable.chars().any(char::is_lowercase) {
+                true => variable.to_string(),
+                false => variable.to_lowercase(),
+            };
+            variables.insert(variable, Value::String(value.to_string()));
+            continue;
+        }
+        let mut key = key.to_lowercase();
+        if let Some((_, field)) = ALIASES.iter().find(|(alias, _)| *alias == key) {
+            key = field.to_string();
+        }
+        if key == "config_files" {
+            config_files = parse(name, Kind::Flag, value)?.as_bool();
+            continue;
+        }
+        let Some(&(field, kind)) = SETTINGS.iter().find(|(field, _)| *field == key) else {
+            log::warn!("Ignoring unknown environment variable {}", name);
+            continue;
+        };
+        let setting = Value::Object(serde_json::Map::from_iter([(field.to_string(), parse(name, kind, value)?)]));
+        // Checking each variable on its own names the one that is wrong
+        serde_json::from_value::<Config>(setting.clone())
+            .map_err(|e| Mark2PdfError::ConfigError(format!("{}: {}", name, e)))?;
+        if let Value::Object(setting) = setting {
+            overlay.extend(setting);
+        }
+    }
+    if !variables.is_empty() {
+        overlay.insert("variables".to_string(), Value::Object(variables));
+    }
+    Ok((Value::Object(overlay), config_files))
+}
+
+fn parse(name: &str, kind: Kind, value: &str) -> Result<Value> {
+    let invalid = |expected: &str| Mark2PdfError::ConfigError(format!("{}: expected {}, got '{}'", name, expected, value));
+    let number = || value.trim().parse::<f64>().ok().and_then(serde_json::Number::from_f64);
+    let json = || serde_json::from_str::<Value>(value).map_err(|e| Mark2PdfError::ConfigError(format!("{}: invalid JSON: {}", name, e)));
+    Ok(match kind {
+        Kind::Text => Value::String(value.to_string()),
+        Kind::Number => Value::Number(number().ok_or_else(|| invalid("a number"))?),
+        Kind::Flag => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Value::Bool(true),
+            "0" | "false" | "no" | "off" => Value::Bool(false),
+            _ => return Err(invalid("true or false")),
+        },
+        Kind::Length => number().map_or_else(|| Value::String(value.to_string()), Value::Number),
+        Kind::Paths => env::split_paths(value)
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| Value::String(path.to_string_lossy().into_owned()))
+            .collect(),
+        Kind::List => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| Value::String(item.to_string()))
+            .collect(),
+        Kind::Json => json()?,
+        Kind::PageFormat | Kind::Watermark if value.trim_start().starts_with('{') => json()?,
+        Kind::PageFormat => Value::String(value.to_string()),
+        Kind::Watermark => Value::Object(serde_json::Map::from_iter([("text".to_string(), Value::String(value.to_string()))])),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Margins, PageFormat, Watermark};
+    use crate::core::theme::NamedTheme;
+
+    #[test]
+    fn test_environment_overrides() {
+        let vars = [
+            ("MARK2PDF_PAGE_FORMAT", "letter"),
+            ("MARK2PDF_MARGIN", "15"),
+            ("MARK2PDF_FONT_SIZE", "12.5"),
+            ("MARK2PDF_ENABLE_GFM", "off"),
+            ("MARK2PDF_THEME", "academic"),
+            ("MARK2PDF_KEYWORDS", "q3, finance"),
+            ("MARK2PDF_WATERMARK", r#"{ "text": "COPY", "opacity": 0.1 }"#),
+            ("MARK2PDF_VAR_RELEASE", "7"),
+            ("MARK2PDF_VAR_releaseDate", "2024-03-05"),
+            ("MARK2PDF_CONFIG_FILES", "no"),
+            ("HOME", "/root"),
+        ];
+        let mut config = Config::new();
+        config.merge_vars(vars).unwrap();
+        assert_eq!(config.page_format, PageFormat::Letter);
+        assert_eq!(config.margins, Margins::uniform(15.0));
+        assert_eq!(config.font_size, 12.5);
+        assert!(!config.enable_gfm);
+        assert_eq!(config.theme, NamedTheme::Academic);
+        assert_eq!(config.keywords, vec!["q3", "finance"]);
+        assert_eq!(config.watermark, Some(Watermark::text("COPY").with_opacity(0.1)));
+        assert_eq!(config.variables["release"], "7");
+        assert_eq!(config.variables["releaseDate"], "2024-03-05");
+        assert!(!config.config_files);
+    }
+
+    #[test]
+    fn test_bad_environment_values_name_the_variable() {
+        for (name, value) in [
+            ("MARK2PDF_FONT_SIZE", "large"),
+            ("MARK2PDF_ENABLE_MATH", "maybe"),
+            ("MARK2PDF_ORIENTATION", "sideways"),
+            ("MARK2PDF_MARGINS", "wide"),
+            ("MARK2PDF_CUSTOM_THEME", "{"),
+        ] {
+            let error = Config::new().merge_vars([(name, value)]).unwrap_err();
+            assert!(matches!(error, Mark2PdfError::ConfigError(ref message) if message.starts_with(name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Configuration files. Settings are layered with increasing precedence:
+//! built-in defaults, the user's config, project configs from the filesystem
+//! root down to the document's directory, `MARK2PDF_*` environment variables
+//! when enabled (see [`crate::config_env`]), the document's front matter, and finally
+//! options given explicitly through the API or command line.
+//!
+//! A config file is either `mark2pdf.toml` or `.mark2pdfrc.json` and uses the
+//! [`Config`] field names as keys:
+//!
+//! ```toml
+//! page_format = "Letter"
+//! margins = "1in 0.75in"
+//! theme = "github"
+//! stylesheets = ["styles/print.css"]
+//!
+//! [variables]
+//! company = "Acme"
+//! ```
+//!
+//! Relative paths are relative to the file that contains them.
+
+use crate::config::Config;
+use crate::error::{Mark2PdfError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Names of config files, looked for in each directory.
+pub const FILE_NAMES: [&str; 2] = ["mark2pdf.toml", ".mark2pdfrc.json"];
+
+/// The config files that apply to a document, lowest precedence first.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ConfigFiles {
+    pub paths: Vec<PathBuf>,
+}
+
+impl ConfigFiles {
+    /// Finds the user's config file and every project config file from the
+    /// filesystem root down to `dir`.
+    pub fn discover(dir: &Path) -> Result<Self> {
+        let mut paths = Vec::new();
+        if let Some(user_dir) = user_config_dir() {
+            paths.extend(find_in(&user_dir)?);
+        }
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        let mut project = Vec::new();
+        for ancestor in dir.ancestors() {
+            project.extend(find_in(ancestor)?);
+        }
+        // The user directory may also be an ancestor of the document
+        project.retain(|path| !paths.contains(path));
+        paths.extend(project.into_iter().rev());
+        Ok(Self { paths })
+    }
+
+    /// The defaults with each file's settings applied in turn.
+    pub fn load(&self) -> Result<Config> {
+        let mut config = Config::default();
+        for path in &self.paths {
+            let layer = read_layer(path)?;
+            config
+                .merge_value(layer)
+                .map_err(|e| Mark2PdfError::ConfigError(format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Where the user-level config file lives, e.g. `~/.config/mark2pdf`.
+pub fn user_config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("mark2pdf"))
+}
+
+// Both kinds of file in one directory would leave their order ambiguous
+fn find_in(dir: &Path) -> Result<Option<PathBuf>> {
+    let found: Vec<PathBuf> = FILE_NAMES.iter().map(|name| dir.join(name)).filter(|path| path.is_file()).collect();
+    match found.as_slice() {
+        [] => Ok(None),
+        [path] => Ok(Some(path.clone())),
+        _ => Err(Mark2PdfError::ConfigError(format!(
+            "{}: found both {} and {}; keep only one",
+            dir.display(),
+            FILE_NAMES[0],
+            FILE_NAMES[1]
+        ))),
+    }
+}
+
+// Reads the settings a file sets, with its relative paths resolved
+fn read_layer(path: &Path) -> Result<serde_json::Value> {
+    let error = |message: String| Mark2PdfError::ConfigError(format!("{}: {}", path.display(), message));
+    let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    let value: serde_json::Value = if path.extension().is_some_and(|ext| ext == "toml") {
+        let table: toml::Table = toml::from_str(&text).map_err(|e| error(e.to_string()))?;
+        serde_json::to_value(table).map_err(|e| error(e.to_string()))?
+    } else {
+        serde_json::from_str(&text).map_err(|e| error(e.to_string()))?
+    };
+
+    // Checking the file on its own points errors at the file that has them
+    resolve_layer(value, path.parent().unwrap_or(Path::new(""))).map_err(|e| match e {
+        Mark2PdfError::ConfigError(message) => error(message),
+        other => other,
+    })
+}
+
+// Checks the settings in `value`, returning them with relative paths made
+// relative to `base`
+pub(crate) fn resolve_layer(value: serde_json::Value, base: &Path) -> Result<serde_json::Value> {
+    let invalid = |e: serde_json::Error| Mark2PdfError::ConfigError(e.to_string());
+    let mut layer: Config = serde_json::from_value(value.clone()).map_err(invalid)?;
+    layer.resolve_paths(base);
+    let mut resolved = serde_json::to_value(&layer).map_err(invalid)?;
+    if let (serde_json::Value::Object(resolved), serde_json::Value::Object(set)) = (&mut resolved, &value) {
+        resolved.retain(|key, _| set.contains_key(key) || set.contains_key(alias_of(key)));
+    }
+    Ok(resolved)
+}
+
+// The singular spellings `Config` also accepts
+fn alias_of(key: &str) -> &str {
+    match key {
+        "margins" => "margin",
+        "stylesheets" => "stylesheet",
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PageFormat, Watermark};
+    use crate::core::theme::NamedTheme;
+
+    #[test]
+    fn test_project_files_override_user_files() {
+        let root = tempfile::tempdir().unwrap();
+        let project = root.path().join("project");
+        let docs = project.join("docs");
+        fs::create_dir_all(&docs).unwrap();
+        let user = root.path().join("user.toml");
+        fs::write(&user, "page_format = \"A5\"\nfont_size = 10\ntheme = \"dark\"\n[variables]\ncompany = \"Acme\"\nyear = \"2024\"\n").unwrap();
+        fs::write(project.join("mark2pdf.toml"), "theme = \"github\"\nstylesheet = [\"print.css\"]\n[variables]\nyear = \"2025\"\n").unwrap();
+        fs::write(docs.join(".mark2pdfrc.json"), r#"{ "font_size": 12, "watermark": { "text": "DRAFT", "angle": 30 } }"#).unwrap();
+
+        let mut files = ConfigFiles::discover(&docs).unwrap();
+        files.paths.retain(|path| path.starts_with(root.path().canonicalize().unwrap()));
+        assert_eq!(files.paths.len(), 2);
+        files.paths.insert(0, user);
+        let config = files.load().unwrap();
+
+        assert_eq!(config.page_format, PageFormat::A5);
+        assert_eq!(config.font_size, 12.0);
+        assert_eq!(config.theme, NamedTheme::Github);
+        assert_eq!(config.stylesheets, vec![project.canonicalize().unwrap().join("print.css")]);
+        assert_eq!(config.variables["company"], "Acme");
+        assert_eq!(config.variables["year"], "2025");
+        assert_eq!(config.watermark, Some(Watermark::text("DRAFT").with_angle(30.0)));
+    }
+
+    #[test]
+    fn test_bad_config_files_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mark2pdf.toml");
+        for text in ["margins = \"wide\"", "page_size = \"A4\"", "font_size = \"big\"", "not toml"] {
+            fs::write(&path, text).unwrap();
+            let files = ConfigFiles { paths: vec![path.clone()] };
+            let error = files.load().err().unwrap();
+            assert!(matches!(error, Mark2PdfError::ConfigError(ref message) if message.contains("mark2pdf.toml")), "{}", text);
+        }
+        fs::write(dir.path().join(".mark2pdfrc.json"), "{}").unwrap();
+        assert!(matches!(ConfigFiles::discover(dir.path()), Err(Mark2PdfError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_explicit_settings_win_over_files() {
+        let mut config = Config::new().with_theme(NamedTheme::Dark).with_font_size(9.0).with_variable("a", "1");
+        config.merge(&Config::new().with_font_size(14.0).with_variable("b", "2")).unwrap();
+        assert_eq!(config.theme, NamedTheme::Dark);
+        assert_eq!(config.font_size, 14.0);
+        assert_eq!(config.variables.len(), 2);
+    }
+}
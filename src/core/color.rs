@@ -0,0 +1,381 @@
+/// A colour as red, green, blue and alpha components between 0 and 1.
+pub type Rgba = (f32, f32, f32, f32);
+
+/// Parses a CSS Color Level 4 value: hex notation with optional alpha,
+/// `rgb()`, `hsl()`, `hwb()`, `lab()`, `lch()`, `oklab()`, `oklch()`, the
+/// named colours and `transparent`. Colours outside sRGB are clipped.
+pub fn parse_color(value: &str) -> Option<Rgba> {
+    let value = value.trim().to_ascii_lowercase();
+    if let Some(hex) = value.strip_prefix('#') {
+        return parse_hex(hex);
+    }
+    if value == "transparent" {
+        return Some((0.0, 0.0, 0.0, 0.0));
+    }
+    if let Some((function, arguments)) = value.strip_suffix(')').and_then(|value| value.split_once('(')) {
+        return parse_function(function.trim(), arguments);
+    }
+    NAMED_COLORS
+        .binary_search_by(|(name, _)| (*name).cmp(value.as_str()))
+        .ok()
+        .map(|index| from_hex(NAMED_COLORS[index].1, 1.0))
+}
+
+fn from_hex(rgb: u32, alpha: f32) -> Rgba {
+    let channel = |shift: u32| ((rgb >> shift) & 0xff) as f32 / 255.0;
+    (channel(16), channel(8), channel(0), alpha)
+}
+
+fn parse_hex(hex: &str) -> Option<Rgba> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    // #rgb and #rgba repeat each digit
+    let expanded: String = match hex.len() {
+        3 | 4 => hex.chars().flat_map(|c| [c, c]).collect(),
+        6 | 8 => hex.to_string(),
+        _ => return None,
+    };
+    let value = u32::from_str_radix(&expanded, 16).ok()?;
+    Some(match expanded.len() {
+        8 => from_hex(value >> 8, (value & 0xff) as f32 / 255.0),
+        _ => from_hex(value, 1.0),
+    })
+}
+
+fn parse_function(function: &str, arguments: &str) -> Option<Rgba> {
+    // Accepts both the legacy comma syntax and the space syntax with `/ alpha`
+    let (channels, alpha) = match arguments.split_once('/') {
+        Some((channels, alpha)) => (channels, Some(alpha)),
+        None => (arguments, None),
+    };
+    let mut parts: Vec<&str> = channels
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect();
+    let alpha = match (alpha, parts.len()) {
+        (Some(alpha), 3) => parse_alpha(alpha)?,
+        (None, 4) => parse_alpha(parts.pop()?)?,
+        (None, 3) => 1.0,
+        _ => return None,
+    };
+    let (a, b, c) = (parts[0], parts[1], parts[2]);
+
+    let (red, green, blue) = match function {
+        "rgb" | "rgba" => (
+            number_or_percentage(a, 255.0)? / 255.0,
+            number_or_percentage(b, 255.0)? / 255.0,
+            number_or_percentage(c, 255.0)? / 255.0,
+        ),
+        "hsl" | "hsla" => hsl_to_rgb(
+            hue(a)?,
+            number_or_percentage(b, 100.0)? / 100.0,
+            number_or_percentage(c, 100.0)? / 100.0,
+        ),
+        "hwb" => hwb_to_rgb(
+            hue(a)?,
+            number_or_percentage(b, 100.0)? / 100.0,
+            number_or_percentage(c, 100.0)? / 100.0,
+        ),
+        "lab" => lab_to_rgb(
+            number_or_percentage(a, 100.0)?,
+            number_or_percentage(b, 125.0)?,
+            number_or_percentage(c, 125.0)?,
+        ),
+        "lch" => {
+            let (lab_a, lab_b) = from_polar(number_or_percentage(b, 150.0)?, hue(c)?);
+            lab_to_rgb(number_or_percentage(a, 100.0)?, lab_a, lab_b)
+        }
+        "oklab" => oklab_to_rgb(
+            number_or_percentage(a, 1.0)?,
+            number_or_percentage(b, 0.4)?,
+            number_or_percentage(c, 0.4)?,
+        ),
+        "oklch" => {
+            let (lab_a, lab_b) = from_polar(number_or_percentage(b, 0.4)?, hue(c)?);
+            oklab_to_rgb(number_or_percentage(a, 1.0)?, lab_a, lab_b)
+        }
+        _ => return None,
+    };
+    Some((red.clamp(0.0, 1.0), green.clamp(0.0, 1.0), blue.clamp(0.0, 1.0), alpha))
+}
+
+// A number, or a percentage of `full`. `none` counts as zero.
+fn number_or_percentage(value: &str, full: f32) -> Option<f32> {
+    match value.strip_suffix('%') {
+        Some(percentage) => percentage.parse::<f32>().ok().map(|p| p / 100.0 * full),
+        None if value == "none" => Some(0.0),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_alpha(value: &str) -> Option<f32> {
+    number_or_percentage(value.trim(), 1.0).map(|alpha| alpha.clamp(0.0, 1.0))
+}
+
+// Angle in degrees
+fn hue(value: &str) -> Option<f32> {
+    let units = [("deg", 1.0), ("grad", 0.9), ("rad", 180.0 / std::f32::consts::PI), ("turn", 360.0)];
+    for (unit, degrees) in units {
+        if let Some(number) = value.strip_suffix(unit) {
+            return number.parse::<f32>().ok().map(|n| n * degrees);
+        }
+    }
+    number_or_percentage(value, 0.0)
+}
+
+fn from_polar(chroma: f32, hue: f32) -> (f32, f32) {
+    let radians = hue.to_radians();
+    (chroma * radians.cos(), chroma * radians.sin())
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (f32, f32, f32) {
+    let channel = |n: f32| {
+        let k = (n + hue / 30.0).rem_euclid(12.0);
+        let a = saturation * lightness.min(1.0 - lightness);
+        lightness - a * (k - 3.0).min(9.0 - k).clamp(-1.0, 1.0)
+    };
+    (channel(0.0), channel(8.0), channel(4.0))
+}
+
+fn hwb_to_rgb(hue: f32, whiteness: f32, blackness: f32) -> (f32, f32, f32) {
+    if whiteness + blackness >= 1.0 {
+        let gray = whiteness / (whiteness + blackness);
+        return (gray, gray, gray);
+    }
+    let (r, g, b) = hsl_to_rgb(hue, 1.0, 0.5);
+    let scale = |c: f32| c * (1.0 - whiteness - blackness) + whiteness;
+    (scale(r), scale(g), scale(b))
+}
+
+// CIE Lab with the D50 white point, as CSS defines it
+fn lab_to_rgb(lightness: f32, a: f32, b: f32) -> (f32, f32, f32) {
+    const KAPPA: f32 = 24389.0 / 27.0;
+    const EPSILON: f32 = 216.0 / 24389.0;
+    let fy = (lightness + 16.0) / 116.0;
+    let fx = a / 500.0 + fy;
+    let fz = fy - b / 200.0;
+    let inverse = |f: f32| if f.powi(3) > EPSILON { f.powi(3) } else { (116.0 * f - 16.0) / KAPPA };
+    let x = inverse(fx) * 0.3457 / 0.3585;
+    let y = if lightness > KAPPA * EPSILON { fy.powi(3) } else { lightness / KAPPA };
+    let z = inverse(fz) * (1.0 - 0.3457 - 0.3585) / 0.3585;
+    // XYZ (D50) to linear sRGB, including the Bradford adaptation to D65
+    (
+        gamma(3.134_136 * x - 1.617_386 * y - 0.490_662 * z),
+        gamma(-0.978_795 * x + 1.916_254 * y + 0.033_443 * z),
+        gamma(0.071_955 * x - 0.228_977 * y + 1.405_386 * z),
+    )
+}
+
+fn oklab_to_rgb(lightness: f32, a: f32, b: f32) -> (f32, f32, f32) {
+    let l = (lightness + 0.396_337_8 * a + 0.215_803_76 * b).powi(3);
+    let m = (lightness - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+    let s = (lightness - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+    (
+        gamma(4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s),
+        gamma(-1.268_438 * l + 2.609_757_4 * m - 0.341_319_4 * s),
+        gamma(-0.004_196_086 * l - 0.703_418_6 * m + 1.707_614_7 * s),
+    )
+}
+
+// Linear light to the sRGB transfer curve
+fn gamma(linear: f32) -> f32 {
+    let magnitude = linear.abs();
+    let encoded = if magnitude <= 0.003_130_8 {
+        12.92 * magnitude
+    } else {
+        1.055 * magnitude.powf(1.0 / 2.4) - 0.055
+    };
+    encoded.copysign(linear)
+}
+
+// Sorted by name for binary search
+const NAMED_COLORS: [(&str, u32); 148] = [
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: Option<Rgba>, expected: Rgba) -> bool {
+        let actual = actual.expect("colour should parse");
+        [actual.0 - expected.0, actual.1 - expected.1, actual.2 - expected.2, actual.3 - expected.3]
+            .iter()
+            .all(|difference| difference.abs() < 0.01)
+    }
+
+    #[test]
+    fn test_hex_and_named_colors() {
+        assert_eq!(parse_color("#f00"), Some((1.0, 0.0, 0.0, 1.0)));
+        assert!(close(parse_color("#3498db"), (0.204, 0.596, 0.859, 1.0)));
+        assert!(close(parse_color("#ff000080"), (1.0, 0.0, 0.0, 0.502)));
+        assert!(close(parse_color("#0f08"), (0.0, 1.0, 0.0, 0.533)));
+        assert!(close(parse_color("Navy"), (0.0, 0.0, 0.502, 1.0)));
+        assert!(close(parse_color("rebeccapurple"), (0.4, 0.2, 0.6, 1.0)));
+        assert_eq!(parse_color("transparent").map(|color| color.3), Some(0.0));
+        assert_eq!(parse_color("#12345"), None);
+        assert_eq!(parse_color("notacolor"), None);
+    }
+
+    #[test]
+    fn test_color_functions() {
+        assert!(close(parse_color("rgb(52, 152, 219)"), (0.204, 0.596, 0.859, 1.0)));
+        assert!(close(parse_color("rgba(255, 0, 0, 0.5)"), (1.0, 0.0, 0.0, 0.5)));
+        assert!(close(parse_color("rgb(100% 0% 0% / 25%)"), (1.0, 0.0, 0.0, 0.25)));
+        assert!(close(parse_color("hsl(120, 100%, 25%)"), (0.0, 0.5, 0.0, 1.0)));
+        assert!(close(parse_color("hsla(0.5turn 100% 50% / .3)"), (0.0, 1.0, 1.0, 0.3)));
+        assert!(close(parse_color("hwb(0 0% 0%)"), (1.0, 0.0, 0.0, 1.0)));
+        assert!(close(parse_color("lab(54.29 80.8 69.89)"), (1.0, 0.0, 0.0, 1.0)));
+        assert!(close(parse_color("oklch(62.8% 0.2577 29.23)"), (1.0, 0.0, 0.0, 1.0)));
+        assert!(close(parse_color("oklab(1 0 0)"), (1.0, 1.0, 1.0, 1.0)));
+        assert_eq!(parse_color("rgb(1, 2)"), None);
+    }
+}
@@ -0,0 +1,597 @@
+use markup5ever_rcdom::{Handle, NodeData};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A single `property: value` pair. Property names are lowercased.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub value: String,
+    pub important: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub selectors: Vec<Selector>,
+    pub declarations: Vec<Declaration>,
+}
+
+/// A parsed stylesheet. Rules with selectors or at-rules this engine does not
+/// understand are dropped, as a browser would.
+#[derive(Debug, Clone, Default)]
+pub struct Stylesheet {
+    pub rules: Vec<Rule>,
+}
+
+impl Stylesheet {
+    pub fn parse(css: &str) -> Self {
+        let mut sheet = Self::default();
+        sheet.parse_rules(&strip_comments(css));
+        sheet
+    }
+
+    fn parse_rules(&mut self, css: &str) {
+        let mut rest = css.trim_start();
+        while !rest.is_empty() {
+            let Some(open) = find_top_level(rest, &['{', ';']) else {
+                break;
+            };
+            let prelude = rest[..open].trim();
+            if rest.as_bytes()[open] == b';' {
+                // Statement at-rules such as @import or @charset
+                rest = rest[open + 1..].trim_start();
+                continue;
+            }
+            let close = matching_brace(rest, open).unwrap_or(rest.len());
+            let block = &rest[open + 1..close];
+            rest = rest.get(close + 1..).unwrap_or("").trim_start();
+
+            if let Some(at_rule) = prelude.strip_prefix('@') {
+                let (name, condition) = at_rule.split_once(char::is_whitespace).unwrap_or((at_rule, ""));
+                // Only the media queries a printed page satisfies
+                if name.eq_ignore_ascii_case("media") && media_applies(condition) {
+                    self.parse_rules(block);
+                }
+                continue;
+            }
+            let selectors: Option<Vec<Selector>> = split_top_level(prelude, ',')
+                .into_iter()
+                .map(Selector::parse)
+                .collect();
+            if let Some(selectors) = selectors.filter(|s| !s.is_empty()) {
+                self.rules.push(Rule {
+                    selectors,
+                    declarations: parse_declarations(block),
+                });
+            }
+        }
+    }
+}
+
+fn media_applies(condition: &str) -> bool {
+    split_top_level(condition, ',').iter().any(|query| {
+        let query = query.trim().to_ascii_lowercase();
+        let media = query.split_whitespace().next().unwrap_or("");
+        matches!(media, "" | "all" | "print") || media.starts_with('(')
+    })
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        output.push_str(&rest[..start]);
+        rest = rest[start + 2..].find("*/").map_or("", |end| &rest[start + 2 + end + 2..]);
+    }
+    output.push_str(rest);
+    output
+}
+
+// Byte offset of the first of `targets` outside quotes and brackets
+fn find_top_level(text: &str, targets: &[char]) -> Option<usize> {
+    let mut depth = 0i32;
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, _) if depth == 0 && targets.contains(&c) => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn matching_brace(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut quote = None;
+    for (index, c) in text[open..].char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(index) = find_top_level(rest, &[separator]) {
+        parts.push(&rest[..index]);
+        rest = &rest[index + 1..];
+    }
+    parts.push(rest);
+    parts.into_iter().filter(|part| !part.trim().is_empty()).collect()
+}
+
+/// Splits a property value into its space-separated components, keeping
+/// functions such as `rgb(0 0 0 / 50%)` whole.
+pub fn split_components(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = value.trim();
+    while let Some(index) = find_top_level(rest, &[' ', '\t', '\n', '\r']) {
+        parts.push(&rest[..index]);
+        rest = rest[index + 1..].trim_start();
+    }
+    if !rest.is_empty() {
+        parts.push(rest);
+    }
+    parts
+}
+
+/// Parses the body of a rule or a `style` attribute.
+pub fn parse_declarations(block: &str) -> Vec<Declaration> {
+    split_top_level(block, ';')
+        .into_iter()
+        .filter_map(|declaration| {
+            let (name, value) = declaration.split_once(':')?;
+            let name = name.trim().to_ascii_lowercase();
+            let mut value = value.trim();
+            let important = match value.to_ascii_lowercase().rfind("!important") {
+                Some(index) => {
+                    value = value[..index].trim_end();
+                    true
+                }
+                None => false,
+            };
+            (!name.is_empty() && !value.is_empty()).then(|| Declaration {
+                name,
+                value: value.to_string(),
+                important,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PseudoClass {
+    // `an+b`, 1-based
+    NthChild(i32, i32),
+    LastChild,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Compound {
+    // Set for `*`, which matches any element
+    universal: bool,
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    pseudo_classes: Vec<PseudoClass>,
+}
+
+impl Compound {
+    fn is_empty(&self) -> bool {
+        !self.universal && self.tag.is_none() && self.id.is_none() && self.classes.is_empty() && self.pseudo_classes.is_empty()
+    }
+
+    fn matches(&self, element: &Handle) -> bool {
+        let NodeData::Element { ref name, .. } = element.data else {
+            return false;
+        };
+        if self.tag.as_ref().is_some_and(|tag| !tag.eq_ignore_ascii_case(&name.local)) {
+            return false;
+        }
+        if let Some(id) = &self.id {
+            if attribute(element, "id").as_deref() != Some(id.as_str()) {
+                return false;
+            }
+        }
+        if !self.classes.is_empty() {
+            let class = attribute(element, "class").unwrap_or_default();
+            if !self.classes.iter().all(|c| class.split_whitespace().any(|have| have == c)) {
+                return false;
+            }
+        }
+        self.pseudo_classes.iter().all(|pseudo| {
+            let (index, count) = element_position(element);
+            match *pseudo {
+                PseudoClass::NthChild(a, b) => nth_matches(a, b, index as i32),
+                PseudoClass::LastChild => index == count,
+            }
+        })
+    }
+}
+
+/// A complex selector such as `table tr:nth-child(2n) > td.numeric`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    // Each compound with the combinator linking it to the one before
+    parts: Vec<(Combinator, Compound)>,
+}
+
+impl Selector {
+    /// Returns `None` for selectors using unsupported syntax.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut parts = Vec::new();
+        let mut compound = Compound::default();
+        let mut combinator = Combinator::Descendant;
+        let mut chars = text.trim().chars().peekable();
+        let read_ident = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || matches!(c, '-' | '_')) {
+                ident.push(c);
+                chars.next();
+            }
+            ident
+        };
+
+        while let Some(&c) = chars.peek() {
+            match c {
+                c if c.is_whitespace() || c == '>' => {
+                    let mut child = false;
+                    while let Some(&c) = chars.peek().filter(|c| c.is_whitespace() || **c == '>') {
+                        child |= c == '>';
+                        chars.next();
+                    }
+                    if compound.is_empty() {
+                        return None;
+                    }
+                    parts.push((combinator, std::mem::take(&mut compound)));
+                    combinator = if child { Combinator::Child } else { Combinator::Descendant };
+                }
+                '*' => {
+                    chars.next();
+                    compound.universal = true;
+                }
+                '.' | '#' => {
+                    chars.next();
+                    let ident = read_ident(&mut chars);
+                    if ident.is_empty() {
+                        return None;
+                    }
+                    if c == '.' {
+                        compound.classes.push(ident);
+                    } else {
+                        compound.id = Some(ident);
+                    }
+                }
+                ':' => {
+                    chars.next();
+                    let name = read_ident(&mut chars).to_ascii_lowercase();
+                    let argument = if chars.peek() == Some(&'(') {
+                        chars.next();
+                        let argument: String = chars.by_ref().take_while(|&c| c != ')').collect();
+                        Some(argument)
+                    } else {
+                        None
+                    };
+                    let pseudo = match (name.as_str(), argument) {
+                        ("first-child", None) => PseudoClass::NthChild(0, 1),
+                        ("last-child", None) => PseudoClass::LastChild,
+                        ("nth-child", Some(argument)) => {
+                            let (a, b) = parse_nth(&argument)?;
+                            PseudoClass::NthChild(a, b)
+                        }
+                        _ => return None,
+                    };
+                    compound.pseudo_classes.push(pseudo);
+                }
+                c if c.is_alphabetic() => {
+                    compound.tag = Some(read_ident(&mut chars).to_ascii_lowercase());
+                }
+                _ => return None,
+            }
+        }
+        if compound.is_empty() {
+            return None;
+        }
+        parts.push((combinator, compound));
+        Some(Self { parts })
+    }
+
+    /// Specificity as a single comparable number: ids, then classes and
+    /// pseudo-classes, then type selectors.
+    pub fn specificity(&self) -> u32 {
+        self.parts
+            .iter()
+            .map(|(_, compound)| {
+                compound.id.is_some() as u32 * 10_000
+                    + (compound.classes.len() + compound.pseudo_classes.len()) as u32 * 100
+                    + compound.tag.is_some() as u32
+            })
+            .sum()
+    }
+
+    pub fn matches(&self, element: &Handle) -> bool {
+        matches_parts(&self.parts, element)
+    }
+}
+
+fn matches_parts(parts: &[(Combinator, Compound)], element: &Handle) -> bool {
+    let Some(((combinator, compound), rest)) = parts.split_last() else {
+        return true;
+    };
+    if !compound.matches(element) {
+        return false;
+    }
+    if rest.is_empty() {
+        return true;
+    }
+    let mut ancestor = parent(element);
+    while let Some(node) = ancestor {
+        if matches_parts(rest, &node) {
+            return true;
+        }
+        if *combinator == Combinator::Child {
+            return false;
+        }
+        ancestor = parent(&node);
+    }
+    false
+}
+
+// Parses the `an+b` argument of `:nth-child()`
+fn parse_nth(argument: &str) -> Option<(i32, i32)> {
+    let argument: String = argument.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+    match argument.as_str() {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+    match argument.split_once('n') {
+        Some((a, b)) => {
+            let a = match a {
+                "" | "+" => 1,
+                "-" => -1,
+                _ => a.parse().ok()?,
+            };
+            let b = if b.is_empty() { 0 } else { b.trim_start_matches('+').parse().ok()? };
+            Some((a, b))
+        }
+        None => Some((0, argument.trim_start_matches('+').parse().ok()?)),
+    }
+}
+
+fn nth_matches(a: i32, b: i32, index: i32) -> bool {
+    if a == 0 {
+        return index == b;
+    }
+    let offset = index - b;
+    offset % a == 0 && offset / a >= 0
+}
+
+pub fn parent(node: &Handle) -> Option<Handle> {
+    let weak = node.parent.take()?;
+    let parent = weak.upgrade();
+    node.parent.set(Some(weak));
+    parent
+}
+
+pub fn attribute(element: &Handle, name: &str) -> Option<String> {
+    let NodeData::Element { ref attrs, .. } = element.data else {
+        return None;
+    };
+    let attrs = attrs.borrow();
+    attrs
+        .iter()
+        .find(|attr| attr.name.local.as_ref() == name)
+        .map(|attr| attr.value.to_string())
+}
+
+// 1-based index among element siblings, and the number of element siblings
+fn element_position(element: &Handle) -> (usize, usize) {
+    let Some(parent) = parent(element) else {
+        return (1, 1);
+    };
+    let (mut position, mut count) = (1, 0);
+    for child in parent.children.borrow().iter() {
+        if !matches!(child.data, NodeData::Element { .. }) {
+            continue;
+        }
+        count += 1;
+        if Rc::ptr_eq(child, element) {
+            position = count;
+        }
+    }
+    (position, count)
+}
+
+/// Rewrites relative `url()` references so they point into `base`, the
+/// directory of the stylesheet they were written in.
+pub fn rebase_urls(css: &str, base: &Path) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + 4);
+        output.push_str(before);
+        let Some((reference, end)) = url_reference(after) else {
+            rest = after;
+            break;
+        };
+        if is_relative_url(&reference) {
+            output.push_str(&css_string(&base.join(&reference).to_string_lossy()));
+        } else {
+            output.push_str(&after[..end]);
+        }
+        rest = &after[end..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// The local file the first `url()` in a property value refers to. Other
+/// URLs, such as `data:` or `https:` ones, are not loaded.
+pub fn url_path(value: &str) -> Option<PathBuf> {
+    let start = value.find("url(")? + 4;
+    let (reference, _) = url_reference(&value[start..])?;
+    if let Some(path) = reference.strip_prefix("file://") {
+        return Some(PathBuf::from(path));
+    }
+    (is_relative_url(&reference) || Path::new(&reference).is_absolute()).then(|| PathBuf::from(reference))
+}
+
+/// The unquoted, unescaped reference at the start of a `url(` argument,
+/// with the offset of its closing `)`.
+fn url_reference(text: &str) -> Option<(String, usize)> {
+    let mut quote = None;
+    let end = text.char_indices().find_map(|(i, c)| {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ')') => return Some(i),
+            _ => {}
+        }
+        None
+    })?;
+    let reference = text[..end].trim();
+    let unquoted = ['"', '\''].iter().find_map(|&q| reference.strip_prefix(q)?.strip_suffix(q));
+    Some((unescape(unquoted.unwrap_or(reference)), end))
+}
+
+// Hex escapes keep quotes out of the string, so the quote matching above
+// and in `find_top_level` doesn't need to know about backslashes
+fn css_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' | '\\' | '\n' | '\r' => quoted.push_str(&format!("\\{:x} ", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn unescape(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        let mut hex = String::new();
+        while let Some(digit) = chars.next_if(|d| d.is_ascii_hexdigit() && hex.len() < 6) {
+            hex.push(digit);
+        }
+        if hex.is_empty() {
+            output.extend(chars.next());
+            continue;
+        }
+        chars.next_if(|c| c.is_whitespace());
+        output.push(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).unwrap_or('\u{fffd}'));
+    }
+    output
+}
+
+fn is_relative_url(reference: &str) -> bool {
+    // Anything with a scheme (`data:`, `https:`, `file:`) is left alone
+    let has_scheme = reference
+        .find(':')
+        .is_some_and(|colon| colon > 1 && reference[..colon].chars().all(|c| c.is_ascii_alphanumeric() || c == '+'));
+    !(reference.is_empty() || has_scheme || reference.starts_with('#') || Path::new(reference).is_absolute())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html5ever::parse_document;
+    use html5ever::tendril::TendrilSink;
+    use markup5ever_rcdom::RcDom;
+
+    fn find(node: &Handle, tag: &str, found: &mut Vec<Handle>) {
+        if let NodeData::Element { ref name, .. } = node.data {
+            if name.local.as_ref() == tag {
+                found.push(node.clone());
+            }
+        }
+        for child in node.children.borrow().iter() {
+            find(child, tag, found);
+        }
+    }
+
+    #[test]
+    fn test_parse_rules_and_media() {
+        let sheet = Stylesheet::parse(
+            "/* base */ h1, .title { color: red !important; margin: 0 }\n\
+             @media screen { p { color: blue } }\n\
+             @media print { p { color: green } }\n\
+             a:hover { color: pink }",
+        );
+        assert_eq!(sheet.rules.len(), 2);
+        assert_eq!(sheet.rules[0].selectors.len(), 2);
+        assert!(sheet.rules[0].declarations[0].important);
+        assert_eq!(sheet.rules[1].declarations[0].value, "green");
+    }
+
+    #[test]
+    fn test_selector_matching_and_specificity() {
+        let html = "<table><tr><td>1</td></tr><tr><td class=\"x\" id=\"y\">2</td></tr></table>";
+        let dom = parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(&mut html.as_bytes())
+            .unwrap();
+        let mut cells = Vec::new();
+        find(&dom.document, "td", &mut cells);
+
+        let striped = Selector::parse("table tr:nth-child(2n) td").unwrap();
+        assert!(!striped.matches(&cells[0]));
+        assert!(striped.matches(&cells[1]));
+        assert!(Selector::parse("tr > td.x#y").unwrap().matches(&cells[1]));
+        assert!(!Selector::parse("table > td").unwrap().matches(&cells[1]));
+        assert!(Selector::parse("tr:first-child td").unwrap().matches(&cells[0]));
+
+        assert!(Selector::parse("#y").unwrap().specificity() > Selector::parse("table tr td.x").unwrap().specificity());
+        assert!(Selector::parse("a::before").is_none());
+    }
+
+    #[test]
+    fn test_rebase_relative_urls() {
+        let css = "a { background: url(img/bg.png) } b { background: url('/abs.png') } i { src: url(data:font/woff2;base64,AA) }";
+        let rebased = rebase_urls(css, Path::new("/styles"));
+        assert!(rebased.contains("url(\"/styles/img/bg.png\")"));
+        assert!(rebased.contains("url('/abs.png')"));
+        assert!(rebased.contains("url(data:font/woff2;base64,AA)"));
+    }
+
+    #[test]
+    fn test_rebased_urls_escape_the_path() {
+        let base = Path::new("/styles/a \"quoted\" \\ (dir)");
+        let rebased = rebase_urls("a { background: url('bg.png') white }", base);
+        let block = &rebased[rebased.find('{').unwrap() + 1..rebased.rfind('}').unwrap()];
+        let declarations = parse_declarations(block);
+        assert_eq!(declarations[0].value.split_whitespace().last(), Some("white"));
+        assert_eq!(url_path(&declarations[0].value), Some(base.join("bg.png")));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::core::fonts::FontFace;
+use image::DynamicImage;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Bitmap strike requested from colour emoji fonts; fonts pick the closest
+const EMOJI_PPEM: u16 = 128;
+
+/// Where colour emoji bitmaps come from.
+#[derive(Clone)]
+pub enum EmojiSource {
+    // A CBDT or sbix bitmap font such as Noto Color Emoji
+    Font(FontFace),
+    // A directory of PNG sprites named by codepoint, e.g. `1f680.png`
+    // (Twemoji) or `emoji_u1f680.png` (Noto)
+    Images(PathBuf),
+}
+
+/// Looks up and decodes emoji images, caching the result per cluster.
+pub struct EmojiRenderer {
+    source: EmojiSource,
+    cache: HashMap<String, Option<Arc<DynamicImage>>>,
+}
+
+impl EmojiRenderer {
+    pub fn new(source: EmojiSource) -> Self {
+        Self {
+            source,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn image(&mut self, cluster: &str) -> Option<Arc<DynamicImage>> {
+        if let Some(image) = self.cache.get(cluster) {
+            return image.clone();
+        }
+        let image = match &self.source {
+            EmojiSource::Font(face) => font_image(face, cluster),
+            EmojiSource::Images(dir) => sprite_image(dir, cluster),
+        }
+        .map(Arc::new);
+        self.cache.insert(cluster.to_string(), image.clone());
+        image
+    }
+}
+
+fn font_image(face: &FontFace, cluster: &str) -> Option<DynamicImage> {
+    // Shaping maps ZWJ sequences, skin tones and flags to their ligature glyph
+    let glyph = crate::core::shaping::shape(face, cluster, Some(false)).first()?.glyph_id;
+    let ttf = face.face();
+    let raster = ttf.glyph_raster_image(ttf_parser::GlyphId(glyph), EMOJI_PPEM)?;
+    if raster.format != ttf_parser::RasterImageFormat::PNG {
+        return None;
+    }
+    image::load_from_memory_with_format(raster.data, image::ImageFormat::Png).ok()
+}
+
+fn sprite_image(dir: &Path, cluster: &str) -> Option<DynamicImage> {
+    let codepoints: Vec<String> = cluster.chars().map(|c| format!("{:x}", c as u32)).collect();
+    // Sprite sets usually drop the emoji presentation selector from file names
+    let without_vs16: Vec<String> = codepoints.iter().filter(|c| *c != "fe0f").cloned().collect();
+    let candidates = [
+        format!("{}.png", codepoints.join("-")),
+        format!("{}.png", without_vs16.join("-")),
+        format!("emoji_u{}.png", codepoints.join("_")),
+        format!("emoji_u{}.png", without_vs16.join("_")),
+    ];
+    candidates
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .and_then(|path| image::open(path).ok())
+}
+
+/// Characters that start an emoji presentation sequence. Symbols that also
+/// have a text presentation only count when followed by U+FE0F.
+pub fn is_emoji_start(c: char, next: Option<char>) -> bool {
+    match c as u32 {
+        0x1F000..=0x1FAFF => true,
+        0x231A..=0x231B | 0x23E9..=0x23FA | 0x2600..=0x27BF | 0x2B05..=0x2B55 | 0x3030 | 0x303D => {
+            next == Some('\u{FE0F}')
+        }
+        // Keycaps: digit, `#` or `*` followed by U+FE0F U+20E3
+        _ if c.is_ascii_digit() || c == '#' || c == '*' => next == Some('\u{FE0F}'),
+        _ => false,
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    matches!(c as u32, 0x1F1E6..=0x1F1FF)
+}
+
+/// Byte length of the emoji cluster at the start of `text`: modifiers,
+/// variation selectors, keycaps, tags, ZWJ sequences and flag pairs.
+pub fn cluster_len(text: &str) -> usize {
+    let mut chars = text.char_indices().peekable();
+    let Some((_, first)) = chars.next() else {
+        return 0;
+    };
+    let mut end = first.len_utf8();
+    if is_regional_indicator(first) {
+        if let Some(&(index, c)) = chars.peek() {
+            if is_regional_indicator(c) {
+                return index + c.len_utf8();
+            }
+        }
+        return end;
+    }
+    while let Some(&(index, c)) = chars.peek() {
+        match c as u32 {
+            0xFE0E | 0xFE0F | 0x20E3 | 0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F => {
+                chars.next();
+                end = index + c.len_utf8();
+            }
+            0x200D => {
+                chars.next();
+                match chars.next() {
+                    Some((joined, c)) => end = joined + c.len_utf8(),
+                    None => end = index + c.len_utf8(),
+                }
+            }
+            _ => break,
+        }
+    }
+    end
+}
+
+/// Invisible characters that may be dropped silently when no font has them.
+pub fn is_default_ignorable(c: char) -> bool {
+    matches!(c as u32, 0x200B..=0x200F | 0x2060..=0x2064 | 0xFE00..=0xFE0F | 0xE0000..=0xE0FFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_boundaries() {
+        assert_eq!(cluster_len("🚀 go"), "🚀".len());
+        assert_eq!(cluster_len("👍🏽!"), "👍🏽".len());
+        assert_eq!(cluster_len("👩‍💻x"), "👩‍💻".len());
+        assert_eq!(cluster_len("🇯🇵🇫🇷"), "🇯🇵".len());
+        assert_eq!(cluster_len("1️⃣2"), "1️⃣".len());
+    }
+
+    #[test]
+    fn test_sprite_lookup_by_codepoints() {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbaImage::new(4, 4).save(dir.path().join("1f680.png")).unwrap();
+        let mut renderer = EmojiRenderer::new(EmojiSource::Images(dir.path().to_path_buf()));
+        assert!(renderer.image("🚀").is_some());
+        assert!(renderer.image("🎉").is_none());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::core::fonts::{FontFace, FontFamily, FontStyle};
+use crate::error::{Mark2PdfError, Result};
+use fontdb::{Database, Family, Query, Style, Weight, ID};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const SYSTEM_FONT_DIRS: &[&str] = &["/usr/share/fonts", "/usr/local/share/fonts"];
+const USER_FONT_DIRS: &[&str] = &[".local/share/fonts", ".fonts"];
+
+// Candidates for the CSS generic families, first installed one wins
+const SERIF_FAMILIES: &[&str] = &["DejaVu Serif", "Noto Serif", "Liberation Serif", "Times New Roman"];
+const SANS_SERIF_FAMILIES: &[&str] = &["DejaVu Sans", "Noto Sans", "Liberation Sans", "Arial"];
+const MONOSPACE_FAMILIES: &[&str] = &["DejaVu Sans Mono", "Noto Sans Mono", "Liberation Mono", "Courier New"];
+// Bitmap colour emoji fonts (CBDT or sbix)
+const EMOJI_FAMILIES: &[&str] = &["Noto Color Emoji", "Apple Color Emoji", "JoyPixels"];
+
+/// Index of installed fonts that resolves CSS `font-family` lists to font families.
+pub struct FontDatabase {
+    db: Database,
+    faces: HashMap<ID, FontFace>,
+}
+
+impl FontDatabase {
+    pub fn new() -> Self {
+        Self {
+            db: Database::new(),
+            faces: HashMap::new(),
+        }
+    }
+
+    /// Scans the standard Linux font directories followed by `extra_dirs`.
+    pub fn system(extra_dirs: &[PathBuf]) -> Self {
+        let mut database = Self::new();
+        for dir in SYSTEM_FONT_DIRS {
+            database.load_dir(dir);
+        }
+        if let Some(home) = std::env::var_os("HOME") {
+            for dir in USER_FONT_DIRS {
+                database.load_dir(Path::new(&home).join(dir));
+            }
+        }
+        for dir in extra_dirs {
+            database.load_dir(dir);
+        }
+        database.update_generic_families();
+        database
+    }
+
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        let dir = dir.as_ref();
+        if dir.is_dir() {
+            self.db.load_fonts_dir(dir);
+        }
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.db
+            .load_font_file(path)
+            .map_err(|e| Mark2PdfError::FontError(format!("{}: {}", path.display(), e)))?;
+        self.update_generic_families();
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty()
+    }
+
+    pub fn family_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .db
+            .faces()
+            .filter_map(|face| face.families.first().map(|(name, _)| name.clone()))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn update_generic_families(&mut self) {
+        if let Some(name) = self.first_installed(SERIF_FAMILIES) {
+            self.db.set_serif_family(name);
+        }
+        if let Some(name) = self.first_installed(SANS_SERIF_FAMILIES) {
+            self.db.set_sans_serif_family(name);
+        }
+        if let Some(name) = self.first_installed(MONOSPACE_FAMILIES) {
+            self.db.set_monospace_family(name);
+        }
+    }
+
+    fn first_installed(&self, candidates: &[&str]) -> Option<String> {
+        candidates
+            .iter()
+            .find_map(|candidate| self.canonical_name(candidate))
+    }
+
+    // Family names are matched case-insensitively, as in CSS
+    fn canonical_name(&self, name: &str) -> Option<String> {
+        self.db.faces().find_map(|face| {
+            face.families
+                .iter()
+                .find(|(family, _)| family.eq_ignore_ascii_case(name))
+                .map(|(family, _)| family.clone())
+        })
+    }
+
+    /// Resolves a single family name or generic keyword (`serif`,
+    /// `sans-serif`, `monospace`, `cursive`, `fantasy`).
+    pub fn resolve_family(&mut self, name: &str) -> Result<Option<FontFamily>> {
+        let canonical;
+        let family = match name.to_ascii_lowercase().as_str() {
+            "serif" => Family::Serif,
+            "sans-serif" => Family::SansSerif,
+            "monospace" => Family::Monospace,
+            "cursive" => Family::Cursive,
+            "fantasy" => Family::Fantasy,
+            _ => match self.canonical_name(name) {
+                Some(found) => {
+                    canonical = found;
+                    Family::Name(&canonical)
+                }
+                None => return Ok(None),
+            },
+        };
+        let family_name = self.db.family_name(&family).to_string();
+
+        let mut ids = Vec::new();
+        for style in [FontStyle::Regular, FontStyle::Bold, FontStyle::Italic, FontStyle::BoldItalic] {
+            let (weight, slant) = match style {
+                FontStyle::Regular => (Weight::NORMAL, Style::Normal),
+                FontStyle::Bold => (Weight::BOLD, Style::Normal),
+                FontStyle::Italic => (Weight::NORMAL, Style::Italic),
+                FontStyle::BoldItalic => (Weight::BOLD, Style::Italic),
+            };
+            let query = Query {
+                families: &[family],
+                weight,
+                style: slant,
+                ..Default::default()
+            };
+            match self.db.query(&query) {
+                Some(id) => ids.push((style, id)),
+                None => return Ok(None),
+            }
+        }
+
+        let mut resolved: Option<FontFamily> = None;
+        for (style, id) in ids {
+            let face = self.load_face(id)?;
+            resolved = Some(match resolved {
+                None => FontFamily::from_face(face).with_name(&family_name),
+                Some(family) => family.with_variant(style, face),
+            });
+        }
+        Ok(resolved)
+    }
+
+    /// Resolves a CSS `font-family` value such as `"Noto Serif", Georgia, serif`.
+    /// Families that are not installed are skipped.
+    pub fn resolve_list(&mut self, list: &str) -> Result<Vec<FontFamily>> {
+        let mut families = Vec::new();
+        for name in parse_family_list(list) {
+            match self.resolve_family(&name)? {
+                Some(family) => {
+                    if !families.iter().any(|f: &FontFamily| f.name == family.name) {
+                        families.push(family);
+                    }
+                }
+                None => log::warn!("Font family '{}' is not installed", name),
+            }
+        }
+        Ok(families)
+    }
+
+    /// The first installed colour emoji font, if any.
+    pub fn emoji_face(&mut self) -> Result<Option<FontFace>> {
+        let Some(name) = self.first_installed(EMOJI_FAMILIES) else {
+            return Ok(None);
+        };
+        let query = Query {
+            families: &[Family::Name(&name)],
+            ..Default::default()
+        };
+        match self.db.query(&query) {
+            Some(id) => self.load_face(id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn load_face(&mut self, id: ID) -> Result<FontFace> {
+        if let Some(face) = self.faces.get(&id) {
+            return Ok(face.clone());
+        }
+        let info = self
+            .db
+            .face(id)
+            .ok_or_else(|| Mark2PdfError::FontError("font removed from database".to_string()))?;
+        let name = info.post_script_name.clone();
+        let face = self
+            .db
+            .with_face_data(id, |data, index| FontFace::from_bytes(name.clone(), data.to_vec(), index))
+            .ok_or_else(|| Mark2PdfError::FontError(format!("{}: unable to read font data", name)))??;
+        self.faces.insert(id, face.clone());
+        Ok(face)
+    }
+}
+
+impl Default for FontDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a CSS `font-family` value into names, removing quotes.
+pub fn parse_family_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|name| name.trim().trim_matches(|c| c == '"' || c == '\'').trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fonts::test_support::dejavu_dir;
+
+    #[test]
+    fn test_parse_family_list() {
+        assert_eq!(
+            parse_family_list(r#""DejaVu Sans", 'Noto Serif',monospace"#),
+            vec!["DejaVu Sans", "Noto Serif", "monospace"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_installed_family() {
+        let mut db = FontDatabase::new();
+        db.load_dir(dejavu_dir());
+        db.update_generic_families();
+        let families = db.resolve_list("\"dejavu serif\", Missing Font, monospace").unwrap();
+        let names: Vec<&str> = families.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["DejaVu Serif", "DejaVu Sans Mono"]);
+    }
+}
@@ -0,0 +1,576 @@
+use crate::core::emoji::{self, EmojiRenderer};
+use crate::core::shaping::{self, ShapedGlyph};
+use crate::error::{Mark2PdfError, Result};
+use image::DynamicImage;
+use printpdf::{BuiltinFont, IndirectFontRef, PdfDocumentReference};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FontStyle {
+    Regular,
+    Bold,
+    Italic,
+    BoldItalic,
+}
+
+impl FontStyle {
+    pub fn from_flags(is_bold: bool, is_italic: bool) -> Self {
+        match (is_bold, is_italic) {
+            (true, true) => FontStyle::BoldItalic,
+            (true, false) => FontStyle::Bold,
+            (false, true) => FontStyle::Italic,
+            (false, false) => FontStyle::Regular,
+        }
+    }
+}
+
+self_cell::self_cell!(
+    // The font data with the face parsed from it, so shaping and glyph lookups
+    // don't parse the tables again
+    struct ParsedFace {
+        owner: Arc<Vec<u8>>,
+        #[covariant]
+        dependent: ShapingFace,
+    }
+);
+
+type ShapingFace<'a> = rustybuzz::Face<'a>;
+
+/// A TrueType/OpenType face loaded from disk or memory.
+#[derive(Clone)]
+pub struct FontFace {
+    pub name: String,
+    parsed: Arc<ParsedFace>,
+    index: u32,
+}
+
+impl FontFace {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|e| Mark2PdfError::FontError(format!("{}: {}", path.display(), e)))?;
+        Self::from_bytes(path.display().to_string(), data, 0)
+    }
+
+    pub fn from_bytes(name: impl Into<String>, data: Vec<u8>, index: u32) -> Result<Self> {
+        let name = name.into();
+        let parsed = ParsedFace::try_new(Arc::new(data), |data| {
+            let face = ttf_parser::Face::parse(data, index)
+                .map_err(|e| Mark2PdfError::FontError(format!("{}: {}", name, e)))?;
+            Ok::<_, Mark2PdfError>(rustybuzz::Face::from_face(face))
+        })?;
+        Ok(Self {
+            name,
+            parsed: Arc::new(parsed),
+            index,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.parsed.borrow_owner()
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn face(&self) -> &ttf_parser::Face<'_> {
+        self.parsed.borrow_dependent()
+    }
+
+    pub(crate) fn shaping_face(&self) -> &rustybuzz::Face<'_> {
+        self.parsed.borrow_dependent()
+    }
+
+    pub fn has_glyph(&self, c: char) -> bool {
+        self.face().glyph_index(c).is_some()
+    }
+
+    // Ascent, descent and line gap in thousandths of an em
+    fn vertical_metrics(&self) -> (f32, f32, f32) {
+        let face = self.face();
+        let scale = 1000.0 / face.units_per_em() as f32;
+        (
+            face.ascender() as f32 * scale,
+            -(face.descender() as f32) * scale,
+            face.line_gap() as f32 * scale,
+        )
+    }
+
+    // Advance width in thousandths of an em, matching the builtin font metrics
+    fn char_width(&self, c: char) -> f32 {
+        let face = self.face();
+        let units = face.units_per_em() as f32;
+        face.glyph_index(c)
+            .and_then(|id| face.glyph_hor_advance(id))
+            .map(|advance| advance as f32 * 1000.0 / units)
+            .unwrap_or(0.0)
+    }
+}
+
+#[derive(Clone)]
+pub enum FontSource {
+    Builtin(BuiltinFont),
+    External(FontFace),
+}
+
+impl FontSource {
+    pub fn has_glyph(&self, c: char) -> bool {
+        match self {
+            FontSource::Builtin(_) => is_win_ansi(c),
+            FontSource::External(face) => face.has_glyph(c),
+        }
+    }
+
+    pub fn vertical_metrics(&self) -> (f32, f32, f32) {
+        match self {
+            // Ascender and Descender from the AFM files
+            FontSource::Builtin(font) if is_courier(font) => (629.0, 157.0, 0.0),
+            FontSource::Builtin(_) => (718.0, 207.0, 0.0),
+            FontSource::External(face) => face.vertical_metrics(),
+        }
+    }
+
+    fn char_width(&self, c: char) -> f32 {
+        match self {
+            // Courier is monospaced
+            FontSource::Builtin(font) if is_courier(font) => 600.0,
+            FontSource::Builtin(font) => {
+                let bold = matches!(font, BuiltinFont::HelveticaBold | BuiltinFont::HelveticaBoldOblique);
+                helvetica_width(c, bold) as f32
+            }
+            FontSource::External(face) => face.char_width(c),
+        }
+    }
+}
+
+/// A family of up to four style variants. Missing variants reuse the regular face.
+#[derive(Clone)]
+pub struct FontFamily {
+    pub name: String,
+    regular: FontSource,
+    bold: FontSource,
+    italic: FontSource,
+    bold_italic: FontSource,
+}
+
+impl FontFamily {
+    pub fn helvetica() -> Self {
+        Self {
+            name: "Helvetica".to_string(),
+            regular: FontSource::Builtin(BuiltinFont::Helvetica),
+            bold: FontSource::Builtin(BuiltinFont::HelveticaBold),
+            italic: FontSource::Builtin(BuiltinFont::HelveticaOblique),
+            bold_italic: FontSource::Builtin(BuiltinFont::HelveticaBoldOblique),
+        }
+    }
+
+    pub fn courier() -> Self {
+        Self {
+            name: "Courier".to_string(),
+            regular: FontSource::Builtin(BuiltinFont::Courier),
+            bold: FontSource::Builtin(BuiltinFont::CourierBold),
+            italic: FontSource::Builtin(BuiltinFont::CourierOblique),
+            bold_italic: FontSource::Builtin(BuiltinFont::CourierBoldOblique),
+        }
+    }
+
+    pub fn from_face(face: FontFace) -> Self {
+        let source = FontSource::External(face.clone());
+        Self {
+            name: face.name,
+            regular: source.clone(),
+            bold: source.clone(),
+            italic: source.clone(),
+            bold_italic: source,
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_variant(mut self, style: FontStyle, face: FontFace) -> Self {
+        let source = FontSource::External(face);
+        match style {
+            FontStyle::Regular => self.regular = source,
+            FontStyle::Bold => self.bold = source,
+            FontStyle::Italic => self.italic = source,
+            FontStyle::BoldItalic => self.bold_italic = source,
+        }
+        self
+    }
+
+    pub fn variant(&self, style: FontStyle) -> &FontSource {
+        match style {
+            FontStyle::Regular => &self.regular,
+            FontStyle::Bold => &self.bold,
+            FontStyle::Italic => &self.italic,
+            FontStyle::BoldItalic => &self.bold_italic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontKey {
+    pub family: usize,
+    pub style: FontStyle,
+}
+
+/// What a run is drawn with.
+#[derive(Debug, Clone)]
+pub enum RunFont {
+    Font(FontKey),
+    // Colour emoji drawn as an image filling the em box instead of text
+    Emoji(Arc<DynamicImage>),
+}
+
+/// A piece of text that can be drawn with a single font.
+#[derive(Debug, Clone)]
+pub struct FontRun {
+    pub font: RunFont,
+    pub text: String,
+    // Sum of advance widths in thousandths of an em
+    pub advance: f32,
+    // Shaped glyphs in visual order; empty for builtin fonts, which are
+    // drawn from the text directly
+    pub glyphs: Vec<ShapedGlyph>,
+    // Font ascent, descent and line gap in thousandths of an em
+    pub metrics: (f32, f32, f32),
+}
+
+// Emoji images sit on the em box, slightly below the baseline like glyphs
+const EMOJI_METRICS: (f32, f32, f32) = (880.0, 120.0, 0.0);
+
+impl FontRun {
+    fn emoji(cluster: &str, image: Arc<DynamicImage>) -> Self {
+        Self {
+            text: cluster.to_string(),
+            advance: 1000.0 * image.width() as f32 / image.height().max(1) as f32,
+            glyphs: Vec::new(),
+            metrics: EMOJI_METRICS,
+            font: RunFont::Emoji(image),
+        }
+    }
+
+    pub fn width_mm(&self, font_size: f32) -> f32 {
+        pt_to_mm(self.advance * font_size / 1000.0)
+    }
+
+    pub fn ascent_mm(&self, font_size: f32) -> f32 {
+        pt_to_mm(self.metrics.0 * font_size / 1000.0)
+    }
+
+    pub fn descent_mm(&self, font_size: f32) -> f32 {
+        pt_to_mm(self.metrics.1 * font_size / 1000.0)
+    }
+
+    pub fn line_gap_mm(&self, font_size: f32) -> f32 {
+        pt_to_mm(self.metrics.2 * font_size / 1000.0)
+    }
+}
+
+/// An ordered list of font families. Each character is drawn with the first
+/// family that has a glyph for it.
+pub struct FontChain {
+    families: Vec<FontFamily>,
+    // Indices into `families` tried after any preferred families
+    fallbacks: Vec<usize>,
+    refs: HashMap<FontKey, IndirectFontRef>,
+    missing: BTreeSet<char>,
+    emoji: Option<EmojiRenderer>,
+}
+
+impl FontChain {
+    pub fn new(families: Vec<FontFamily>) -> Self {
+        Self {
+            fallbacks: (0..families.len()).collect(),
+            families,
+            refs: HashMap::new(),
+            missing: BTreeSet::new(),
+            emoji: None,
+        }
+    }
+
+    pub fn builtin() -> Self {
+        Self::new(vec![FontFamily::helvetica()])
+    }
+
+    pub fn push(&mut self, family: FontFamily) {
+        let index = self.register(family);
+        self.fallbacks.retain(|&i| i != index);
+        self.fallbacks.push(index);
+    }
+
+    // Makes `family` the first fallback, ahead of the builtin font
+    pub fn push_front(&mut self, family: FontFamily) {
+        let index = self.register(family);
+        self.fallbacks.retain(|&i| i != index);
+        self.fallbacks.insert(0, index);
+    }
+
+    /// Adds a family that is only used when explicitly preferred, returning
+    /// its index. Families are deduplicated by name.
+    pub fn register(&mut self, family: FontFamily) -> usize {
+        if let Some(index) = self.families.iter().position(|f| f.name == family.name) {
+            return index;
+        }
+        self.families.push(family);
+        self.families.len() - 1
+    }
+
+    pub fn set_emoji(&mut self, renderer: EmojiRenderer) {
+        self.emoji = Some(renderer);
+    }
+
+    pub fn has_emoji(&self) -> bool {
+        self.emoji.is_some()
+    }
+
+    pub fn families(&self) -> &[FontFamily] {
+        &self.families
+    }
+
+    pub fn source(&self, key: FontKey) -> &FontSource {
+        self.families[key.family].variant(key.style)
+    }
+
+    fn select(&self, c: char, style: FontStyle, preferred: &[usize]) -> Option<usize> {
+        preferred
+            .iter()
+            .chain(self.fallbacks.iter())
+            .copied()
+            .find(|&index| self.families[index].variant(style).has_glyph(c))
+    }
+
+    pub fn split_runs(&mut self, text: &str, style: FontStyle) -> Vec<FontRun> {
+        self.split_runs_with(text, style, &[], false)
+    }
+
+    /// Splits `text` into runs of consecutive characters that share a font,
+    /// trying the `preferred` families before the fallback chain.
+    /// Emoji sequences become runs of their own when an emoji renderer is
+    /// set and has an image for them.
+    /// Characters no font can render are dropped and remembered for
+    /// [`FontChain::report_missing`]. Runs are returned in visual order, so
+    /// right-to-left text comes back reversed.
+    pub fn split_runs_with(&mut self, text: &str, style: FontStyle, preferred: &[usize], rtl: bool) -> Vec<FontRun> {
+        let mut runs: Vec<FontRun> = Vec::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if let Some(renderer) = self.emoji.as_mut() {
+                if emoji::is_emoji_start(c, rest[c.len_utf8()..].chars().next()) {
+                    let len = emoji::cluster_len(rest);
+                    if let Some(image) = renderer.image(&rest[..len]) {
+                        runs.push(FontRun::emoji(&rest[..len], image));
+                        rest = &rest[len..];
+                        continue;
+                    }
+                }
+            }
+            rest = &rest[c.len_utf8()..];
+
+            let c = if c.is_whitespace() { ' ' } else { c };
+            if c.is_control() {
+                continue;
+            }
+            let Some(family) = self.select(c, style, preferred) else {
+                if !emoji::is_default_ignorable(c) {
+                    self.missing.insert(c);
+                }
+                continue;
+            };
+            let key = FontKey { family, style };
+            let width = self.source(key).char_width(c);
+            match runs.last_mut() {
+                Some(run) if matches!(run.font, RunFont::Font(font) if font == key) => {
+                    run.text.push(c);
+                    run.advance += width;
+                }
+                _ => runs.push(FontRun {
+                    font: RunFont::Font(key),
+                    text: c.to_string(),
+                    advance: width,
+                    glyphs: Vec::new(),
+                    metrics: self.source(key).vertical_metrics(),
+                }),
+            }
+        }
+        for run in &mut runs {
+            let RunFont::Font(key) = run.font else { continue };
+            match self.source(key) {
+                FontSource::External(face) => {
+                    run.glyphs = shaping::shape(face, &run.text, Some(rtl));
+                    run.advance = run.glyphs.iter().map(|glyph| glyph.x_advance).sum();
+                }
+                FontSource::Builtin(_) if rtl => {
+                    run.text = run.text.chars().rev().map(mirror).collect();
+                }
+                FontSource::Builtin(_) => {}
+            }
+        }
+        if rtl {
+            runs.reverse();
+        }
+        runs
+    }
+
+    pub fn font_ref(&mut self, doc: &PdfDocumentReference, key: FontKey) -> Result<IndirectFontRef> {
+        if let Some(font) = self.refs.get(&key) {
+            return Ok(font.clone());
+        }
+        let font = match self.source(key) {
+            FontSource::Builtin(builtin) => doc.add_builtin_font(*builtin)?,
+            FontSource::External(face) => doc.add_external_font(face.data())?,
+        };
+        self.refs.insert(key, font.clone());
+        Ok(font)
+    }
+
+    pub fn missing_chars(&self) -> &BTreeSet<char> {
+        &self.missing
+    }
+
+    pub fn report_missing(&self) {
+        if self.missing.is_empty() {
+            return;
+        }
+        let chars: Vec<String> = self
+            .missing
+            .iter()
+            .map(|c| format!("'{}' (U+{:04X})", c, *c as u32))
+            .collect();
+        log::warn!("No configured font has glyphs for: {}", chars.join(", "));
+    }
+}
+
+impl Default for FontChain {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+// Bidi mirrored forms of the paired punctuation the builtin fonts cover
+fn mirror(c: char) -> char {
+    match c {
+        '(' => ')',
+        ')' => '(',
+        '[' => ']',
+        ']' => '[',
+        '{' => '}',
+        '}' => '{',
+        '<' => '>',
+        '>' => '<',
+        '«' => '»',
+        '»' => '«',
+        '‹' => '›',
+        '›' => '‹',
+        _ => c,
+    }
+}
+
+fn is_courier(font: &BuiltinFont) -> bool {
+    matches!(
+        font,
+        BuiltinFont::Courier | BuiltinFont::CourierBold | BuiltinFont::CourierOblique | BuiltinFont::CourierBoldOblique
+    )
+}
+
+pub fn pt_to_mm(pt: f32) -> f32 {
+    pt * 25.4 / 72.0
+}
+
+// Characters representable in WinAnsiEncoding, the encoding printpdf uses
+// for the builtin fonts
+pub fn is_win_ansi(c: char) -> bool {
+    matches!(c as u32, 0x20..=0x7E | 0xA0..=0xFF)
+        || matches!(
+            c,
+            '€' | '‚' | 'ƒ' | '„' | '…' | '†' | '‡' | 'ˆ' | '‰' | 'Š' | '‹' | 'Œ' | 'Ž' | '‘' | '’' | '“'
+                | '”' | '•' | '–' | '—' | '˜' | '™' | 'š' | '›' | 'œ' | 'ž' | 'Ÿ'
+        )
+}
+
+// Advance widths from the Helvetica and Helvetica-Bold AFM files, indexed
+// from U+0020. The oblique variants share these metrics.
+const HELVETICA_ASCII: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+const HELVETICA_BOLD_ASCII: [u16; 95] = [
+    278, 333, 474, 556, 556, 889, 722, 238, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 333, 333, 584, 584, 584, 611, 975, 722, 722, 722, 722, 667,
+    611, 778, 722, 278, 556, 722, 611, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 333, 278, 333, 584, 556, 333, 556, 611, 556, 611, 556, 333, 611, 611, 278, 278, 556,
+    278, 889, 611, 611, 611, 611, 389, 556, 333, 611, 556, 778, 556, 556, 500, 389, 280, 389, 584,
+];
+
+fn helvetica_width(c: char, bold: bool) -> u16 {
+    let table = if bold { &HELVETICA_BOLD_ASCII } else { &HELVETICA_ASCII };
+    match c {
+        ' '..='~' => table[c as usize - 0x20],
+        '\u{A0}' => 278,
+        '‘' | '’' | '‚' => if bold { 278 } else { 222 },
+        '“' | '”' | '„' => if bold { 500 } else { 333 },
+        '…' | '—' | '‰' | '™' => 1000,
+        '•' => 350,
+        _ => 556,
+    }
+}
+
+// Font tests run against DejaVu, as installed by the fonts-dejavu-core
+// package, and fail rather than pass unchecked when it is missing
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::FontFace;
+    use std::path::Path;
+
+    pub const DEJAVU_SANS: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+
+    pub fn dejavu_sans() -> FontFace {
+        FontFace::from_file(DEJAVU_SANS).unwrap_or_else(|e| panic!("font tests need DejaVu Sans (fonts-dejavu-core): {}", e))
+    }
+
+    // The directory the DejaVu fonts are installed in
+    pub fn dejavu_dir() -> &'static Path {
+        dejavu_sans();
+        Path::new(DEJAVU_SANS).parent().expect("font path has a directory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::dejavu_sans;
+    use super::*;
+
+    #[test]
+    fn test_builtin_covers_latin1_only() {
+        let mut chain = FontChain::builtin();
+        let runs = chain.split_runs("café → Ω", FontStyle::Regular);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].text, "café  ");
+        assert!(chain.missing_chars().contains(&'→'));
+        assert!(chain.missing_chars().contains(&'Ω'));
+    }
+
+    #[test]
+    fn test_fallback_font_splits_runs() {
+        let mut chain = FontChain::builtin();
+        chain.push(FontFamily::from_face(dejavu_sans()));
+        let runs = chain.split_runs("Greek Ωμέγα!", FontStyle::Bold);
+        let family = |run: &FontRun| match run.font {
+            RunFont::Font(key) => Some(key.family),
+            RunFont::Emoji(_) => None,
+        };
+        let texts: Vec<(&str, Option<usize>)> = runs.iter().map(|r| (r.text.as_str(), family(r))).collect();
+        assert_eq!(texts, vec![("Greek ", Some(0)), ("Ωμέγα", Some(1)), ("!", Some(0))]);
+        assert!(chain.missing_chars().is_empty());
+    }
+}
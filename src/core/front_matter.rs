@@ -0,0 +1,201 @@
+use crate::config::Config;
+use crate::config_file;
+use crate::error::{Mark2PdfError, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings read from a YAML block delimited by `---` at the top of a
+/// document. Keys are the `Config` field names, as in config files, plus
+/// the document's own `variables`, `stylesheets` and `css`.
+#[derive(Default)]
+pub struct FrontMatter {
+    // Config settings in the form config files give them
+    settings: serde_json::Map<String, Value>,
+    // Values for `{{ name }}` placeholders in the body
+    pub variables: BTreeMap<String, serde_yaml::Value>,
+    // Document styles come after those from the configuration
+    pub stylesheets: PathList,
+    pub css: Option<String>,
+    unknown: Vec<String>,
+}
+
+// Accepts either a single value or a list
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+pub type PathList = OneOrMany<PathBuf>;
+
+impl FrontMatter {
+    /// Splits the front matter off `content`, returning it with the Markdown after it.
+    pub fn split(content: &str) -> Result<(Self, &str)> {
+        match split_front_matter(content) {
+            (Some(yaml), body) => Ok((Self::parse(yaml)?, body)),
+            (None, body) => Ok((Self::default(), body)),
+        }
+    }
+
+    pub fn parse(yaml: &str) -> Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| Mark2PdfError::MarkdownError(format!("invalid front matter: {}", e));
+        // An empty block is YAML null rather than an empty mapping
+        if yaml.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let keys: BTreeMap<String, serde_yaml::Value> = serde_yaml::from_str(yaml).map_err(|e| invalid(&e))?;
+        let fields = match serde_json::to_value(Config::default()).map_err(|e| invalid(&e))? {
+            Value::Object(fields) => fields,
+            _ => serde_json::Map::new(),
+        };
+        let mut front_matter = Self::default();
+        for (key, value) in keys {
+            match key.as_str() {
+                "variables" | "vars" => front_matter.variables = serde_yaml::from_value(value).map_err(|e| invalid(&e))?,
+                "stylesheets" | "stylesheet" => front_matter.stylesheets = serde_yaml::from_value(value).map_err(|e| invalid(&e))?,
+                "css" => front_matter.css = serde_yaml::from_value(value).map_err(|e| invalid(&e))?,
+                _ => {
+                    let key = if key == "page_size" { "page_format".to_string() } else { key };
+                    if !fields.contains_key(&key) && key != "margin" {
+                        front_matter.unknown.push(key);
+                        continue;
+                    }
+                    let value = match (key.as_str(), serde_json::to_value(value).map_err(|e| invalid(&e))?) {
+                        // Shorthands for a single keyword and a text watermark
+                        ("keywords", Value::String(keyword)) => Value::Array(vec![Value::String(keyword)]),
+                        ("watermark", Value::String(text)) => serde_json::json!({ "text": text }),
+                        (_, value) => value,
+                    };
+                    front_matter.settings.insert(key, value);
+                }
+            }
+        }
+        Ok(front_matter)
+    }
+
+    pub fn unknown_keys(&self) -> impl Iterator<Item = &str> {
+        self.unknown.iter().map(String::as_str)
+    }
+
+    /// Overrides the settings the front matter names, with relative paths
+    /// found in `base_dir`. Stylesheets and CSS are left to the Markdown
+    /// processor, which adds them after the configured ones.
+    pub fn apply_to(&self, config: &mut Config, base_dir: Option<&Path>) -> Result<()> {
+        for key in self.unknown_keys() {
+            log::warn!("Ignoring unknown front matter key '{}'; template values belong under 'variables'", key);
+        }
+        let invalid = |e: Mark2PdfError| match e {
+            Mark2PdfError::ConfigError(message) => Mark2PdfError::ConfigError(format!("front matter: {}", message)),
+            other => other,
+        };
+
+        let settings = config_file::resolve_layer(Value::Object(self.settings.clone()), base_dir.unwrap_or(Path::new("")));
+        config.merge_value(settings.map_err(invalid)?).map_err(invalid)?;
+        for (name, value) in &self.variables {
+            let value = match value {
+                serde_yaml::Value::String(text) => text.clone(),
+                serde_yaml::Value::Number(number) => number.to_string(),
+                serde_yaml::Value::Bool(flag) => flag.to_string(),
+                _ => {
+                    return Err(Mark2PdfError::ConfigError(format!(
+                        "front matter: variable '{}' must be a string, number or boolean",
+                        name
+                    )))
+                }
+            };
+            config.variables.insert(name.clone(), value);
+        }
+        Ok(())
+    }
+}
+
+// Splits a leading `---` delimited YAML block from the Markdown after it
+fn split_front_matter(content: &str) -> (Option<&str>, &str) {
+    let Some(rest) = content.strip_prefix("---\n").or_else(|| content.strip_prefix("---\r\n")) else {
+        return (None, content);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if matches!(line.trim_end(), "---" | "...") {
+            return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+    (None, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Orientation, PageFormat, Watermark};
+    use crate::core::theme::NamedTheme;
+
+    #[test]
+    fn test_front_matter_maps_onto_config() {
+        let yaml = "title: Report\nauthor: Ann\nkeywords: [a, b]\npage_format: letter\norientation: landscape\n\
+                    margin: 1in 2cm\ntheme: dark\nfooter: \"{page} / {pages}\"\nwatermark: DRAFT\nversion: 3\n";
+        let document = format!("---\n{}---\n# Body\n", yaml);
+        let (front_matter, body) = FrontMatter::split(&document).unwrap();
+        assert_eq!(body, "# Body\n");
+        assert_eq!(front_matter.unknown_keys().collect::<Vec<_>>(), vec!["version"]);
+
+        let mut config = Config::new();
+        front_matter.apply_to(&mut config, None).unwrap();
+        assert_eq!(config.title.as_deref(), Some("Report"));
+        assert_eq!(config.keywords, vec!["a", "b"]);
+        assert_eq!(config.page_format, PageFormat::Letter);
+        assert_eq!(config.orientation, Orientation::Landscape);
+        assert_eq!(config.margins, "1in 2cm".parse().unwrap());
+        assert_eq!(config.theme, NamedTheme::Dark);
+        assert_eq!(config.footer.as_deref(), Some("{page} / {pages}"));
+        assert_eq!(config.watermark, Some(Watermark::text("DRAFT")));
+    }
+
+    #[test]
+    fn test_front_matter_accepts_every_config_key() {
+        let yaml = "page_size: { width: 100, height: 150 }
+enable_gfm: false
+fallback_fonts: [fonts/extra.ttf]
+\
+                    page_background_image: paper.png
+watermark: { image: seal.png, angle: 30, pages: \"2-\" }
+";
+        let front_matter = FrontMatter::parse(yaml).unwrap();
+        assert_eq!(front_matter.unknown_keys().count(), 0);
+
+        let mut config = Config::new();
+        front_matter.apply_to(&mut config, Some(Path::new("docs"))).unwrap();
+        assert_eq!(config.page_format, PageFormat::Custom { width: 100.0, height: 150.0 });
+        assert!(!config.enable_gfm);
+        assert_eq!(config.fallback_fonts, vec![PathBuf::from("docs/fonts/extra.ttf")]);
+        assert_eq!(config.page_background_image, Some(PathBuf::from("docs/paper.png")));
+        let watermark = Watermark::image("docs/seal.png").with_angle(30.0).with_pages("2-".parse().unwrap());
+        assert_eq!(config.watermark, Some(watermark));
+    }
+
+    #[test]
+    fn test_bad_front_matter_values_are_config_errors() {
+        let (front_matter, _) = FrontMatter::split("---\npage_format: B7\n---\n").unwrap();
+        let error = front_matter.apply_to(&mut Config::new(), None).unwrap_err();
+        assert!(matches!(error, Mark2PdfError::ConfigError(ref message) if message.starts_with("front matter:")));
+        assert!(FrontMatter::split("---\n---\nbody").unwrap().1 == "body");
+    }
+}
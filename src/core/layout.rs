@@ -0,0 +1,264 @@
+use crate::core::pdf::{TextDirection, TextFormat};
+use crate::error::Result;
+use std::ops::Range;
+use unicode_bidi::{Level, ParagraphBidiInfo};
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+
+// Kinsoku shori: characters that must not begin a line
+const NOT_AT_LINE_START: &str = "、。，．・：；？！‼⁇⁈⁉ゝゞヽヾーァィゥェォッャュョヮヵヶぁぃぅぇぉっゃゅょゎゕゖㇰㇱㇲㇳㇴㇵㇶㇷㇸㇹㇺㇻㇼㇽㇾㇿ々〻‐゠–〜～」』）］｝〕〉》】〙〗〟’”｠»";
+// ...and characters that must not end one
+const NOT_AT_LINE_END: &str = "「『（［｛〔〈《【〘〖〝‘“｟«";
+
+/// A piece of inline text together with the format it was written in.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub text: String,
+    pub format: TextFormat,
+}
+
+/// The inline content between two hard line breaks, concatenated into one
+/// logical string so it can be broken into lines and reordered as a whole.
+#[derive(Debug, Clone)]
+pub struct Paragraph {
+    pub text: String,
+    pub fragments: Vec<Fragment>,
+    // Byte range of each fragment within `text`
+    spans: Vec<Range<usize>>,
+}
+
+impl Paragraph {
+    pub fn new(fragments: Vec<Fragment>) -> Self {
+        let mut text = String::new();
+        let mut spans = Vec::with_capacity(fragments.len());
+        for fragment in &fragments {
+            let start = text.len();
+            text.push_str(&fragment.text);
+            spans.push(start..text.len());
+        }
+        Self { text, fragments, spans }
+    }
+
+    /// Splits buffered fragments at `\n` into separate paragraphs.
+    pub fn split_hard_breaks(fragments: Vec<Fragment>) -> Vec<Paragraph> {
+        let mut paragraphs = Vec::new();
+        let mut current: Vec<Fragment> = Vec::new();
+        for fragment in fragments {
+            let mut parts = fragment.text.split('\n').peekable();
+            while let Some(part) = parts.next() {
+                if !part.is_empty() {
+                    current.push(Fragment {
+                        text: part.to_string(),
+                        format: fragment.format.clone(),
+                    });
+                }
+                if parts.peek().is_some() {
+                    paragraphs.push(Paragraph::new(std::mem::take(&mut current)));
+                }
+            }
+        }
+        if !current.is_empty() {
+            paragraphs.push(Paragraph::new(current));
+        }
+        paragraphs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// The parts of `range` covered by each fragment, in logical order.
+    pub fn pieces(&self, range: Range<usize>) -> Vec<(Range<usize>, usize)> {
+        self.spans
+            .iter()
+            .enumerate()
+            .filter_map(|(index, span)| {
+                let start = span.start.max(range.start);
+                let end = span.end.min(range.end);
+                (start < end).then_some((start..end, index))
+            })
+            .collect()
+    }
+}
+
+/// A part of a line that has a single fragment and direction, in the order
+/// it is drawn from left to right.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VisualPiece {
+    pub range: Range<usize>,
+    pub fragment: usize,
+    pub rtl: bool,
+}
+
+pub fn base_level(direction: TextDirection) -> Option<Level> {
+    match direction {
+        TextDirection::Ltr => Some(Level::ltr()),
+        TextDirection::Rtl => Some(Level::rtl()),
+        TextDirection::Auto => None,
+    }
+}
+
+/// A position at which a new line may (or must) start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Break {
+    pub offset: usize,
+    pub mandatory: bool,
+}
+
+/// Line break opportunities from the Unicode Line Breaking Algorithm
+/// (UAX #14), with Japanese kinsoku rules applied on top. The end of the
+/// text is always a mandatory break.
+pub fn break_opportunities(text: &str) -> Vec<Break> {
+    linebreaks(text)
+        .filter(|&(offset, opportunity)| {
+            if opportunity == BreakOpportunity::Mandatory || offset == text.len() {
+                return true;
+            }
+            let before = text[..offset].chars().next_back();
+            let after = text[offset..].chars().next();
+            !after.is_some_and(|c| NOT_AT_LINE_START.contains(c))
+                && !before.is_some_and(|c| NOT_AT_LINE_END.contains(c))
+        })
+        .map(|(offset, opportunity)| Break {
+            offset,
+            mandatory: opportunity == BreakOpportunity::Mandatory,
+        })
+        .collect()
+}
+
+/// East Asian scripts written without spaces between words.
+pub fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x2E80..=0x2FDF // CJK radicals, Kangxi radicals
+        | 0x3000..=0x303F // CJK symbols and punctuation
+        | 0x3040..=0x30FF // Hiragana, Katakana
+        | 0x31F0..=0x31FF // Katakana phonetic extensions
+        | 0x3400..=0x4DBF // CJK extension A
+        | 0x4E00..=0x9FFF // CJK unified ideographs
+        | 0xF900..=0xFAFF // CJK compatibility ideographs
+        | 0xFF00..=0xFFEF // Halfwidth and fullwidth forms
+        | 0x20000..=0x3134F // CJK extensions B to G
+    )
+}
+
+fn trim_end(text: &str, range: Range<usize>) -> Range<usize> {
+    let trimmed = text[range.clone()].trim_end_matches(' ');
+    range.start..range.start + trimmed.len()
+}
+
+/// Greedy line filling. Each segment between two break opportunities is
+/// kept whole; a segment wider than `max_width` overflows its own line.
+/// Returned ranges exclude the spaces at the end of each line.
+pub fn break_lines<F>(text: &str, opportunities: &[Break], mut measure: F, max_width: f32) -> Result<Vec<Range<usize>>>
+where
+    F: FnMut(Range<usize>) -> Result<f32>,
+{
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut segment_start = 0;
+    let mut width = 0.0;
+    for opportunity in opportunities {
+        if opportunity.offset <= segment_start {
+            continue;
+        }
+        let segment = segment_start..opportunity.offset;
+        let visible = measure(trim_end(text, segment.clone()))?;
+        if width + visible > max_width && segment_start > line_start {
+            lines.push(trim_end(text, line_start..segment_start));
+            line_start = segment_start;
+            width = 0.0;
+        }
+        width += measure(segment)?;
+        segment_start = opportunity.offset;
+        if opportunity.mandatory && segment_start < text.len() {
+            lines.push(trim_end(text, line_start..segment_start));
+            line_start = segment_start;
+            width = 0.0;
+        }
+    }
+    if line_start < text.len() || lines.is_empty() {
+        lines.push(trim_end(text, line_start..text.len()));
+    }
+    Ok(lines)
+}
+
+/// Reorders one line with the Unicode Bidirectional Algorithm and splits it
+/// at fragment boundaries.
+pub fn visual_pieces(paragraph: &Paragraph, bidi: &ParagraphBidiInfo, line: Range<usize>) -> Vec<VisualPiece> {
+    if line.is_empty() {
+        return Vec::new();
+    }
+    let (levels, runs) = bidi.visual_runs(line);
+    let mut pieces = Vec::new();
+    for run in runs {
+        let rtl = levels[run.start].is_rtl();
+        let mut run_pieces = paragraph.pieces(run);
+        if rtl {
+            run_pieces.reverse();
+        }
+        pieces.extend(
+            run_pieces
+                .into_iter()
+                .map(|(range, fragment)| VisualPiece { range, fragment, rtl }),
+        );
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(text: &str) -> Fragment {
+        Fragment {
+            text: text.to_string(),
+            format: TextFormat::default(),
+        }
+    }
+
+    #[test]
+    fn test_break_lines_at_spaces() {
+        let text = "aaa bb cccc d";
+        let opportunities = break_opportunities(text);
+        let offsets: Vec<usize> = opportunities.iter().map(|b| b.offset).collect();
+        assert_eq!(offsets, vec![4, 7, 12, 13]);
+        let lines = break_lines(text, &opportunities, |range| Ok(range.len() as f32), 7.0).unwrap();
+        let lines: Vec<&str> = lines.into_iter().map(|range| &text[range]).collect();
+        assert_eq!(lines, vec!["aaa bb", "cccc d"]);
+    }
+
+    #[test]
+    fn test_cjk_breaks_between_ideographs_with_kinsoku() {
+        let text = "日本語「テスト」です。";
+        let offsets: Vec<usize> = break_opportunities(text).iter().map(|b| b.offset).collect();
+        let starts: Vec<char> = offsets
+            .iter()
+            .filter_map(|&offset| text[offset..].chars().next())
+            .collect();
+        // Breaks exist between ideographs, but never before closing
+        // brackets or full stops, and never after an opening bracket
+        assert!(starts.contains(&'本'));
+        assert!(!starts.contains(&'」'));
+        assert!(!starts.contains(&'。'));
+        assert!(!starts.contains(&'テ'));
+    }
+
+    #[test]
+    fn test_hard_breaks_split_paragraphs() {
+        let paragraphs = Paragraph::split_hard_breaks(vec![fragment("one\ntwo"), fragment(" three\n")]);
+        let texts: Vec<&str> = paragraphs.iter().map(|p| p.text.as_str()).collect();
+        assert_eq!(texts, vec!["one", "two three"]);
+    }
+
+    #[test]
+    fn test_visual_order_of_mixed_paragraph() {
+        // Hebrew "shalom" followed by an English product name in an RTL paragraph
+        let paragraph = Paragraph::new(vec![fragment("שלום "), fragment("Mark2Pdf")]);
+        let bidi = ParagraphBidiInfo::new(&paragraph.text, base_level(TextDirection::Rtl));
+        let pieces = visual_pieces(&paragraph, &bidi, 0..paragraph.text.len());
+        let order: Vec<(&str, bool)> = pieces
+            .iter()
+            .map(|piece| (&paragraph.text[piece.range.clone()], piece.rtl))
+            .collect();
+        assert_eq!(order, vec![("Mark2Pdf", false), ("שלום ", true)]);
+    }
+}
@@ -1,79 +1,235 @@
+use crate::config::Config;
+use crate::core::front_matter::FrontMatter;
+use crate::core::resources::Resources;
+use crate::core::template;
 use crate::error::{Mark2PdfError, Result};
-use pulldown_cmark::{html, Options, Parser};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag, TagEnd};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-pub struct MarkdownProcessor;
+pub struct MarkdownProcessor {
+    config: Config,
+}
 
 impl MarkdownProcessor {
     pub fn new() -> Self {
-        Self
+        Self::from_config(&Config::default())
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        if config.enable_math {
+            log::warn!("Math rendering is not supported yet; $...$ is left as plain text");
+        }
+        Self { config: config.clone() }
     }
 
+    // Stylesheets named in the file's front matter are found relative to the file
     pub fn process_file<P: AsRef<Path>>(&self, input_path: P) -> Result<String> {
-        let content = fs::read_to_string(input_path)
-            .map_err(|e| Mark2PdfError::IoError(e))?;
-        self.process_content(&content)
+        let input_path = input_path.as_ref();
+        let content = fs::read_to_string(input_path).map_err(Mark2PdfError::IoError)?;
+        Ok(self.process_document(&content, input_path.parent())?.0)
     }
 
     pub fn process_content(&self, content: &str) -> Result<String> {
-        // Set up options for GitHub-flavored Markdown
-        let mut options = Options::empty();
-        options.insert(Options::ENABLE_STRIKETHROUGH);
-        options.insert(Options::ENABLE_TABLES);
-        options.insert(Options::ENABLE_FOOTNOTES);
-        options.insert(Options::ENABLE_TASKLISTS);
-        options.insert(Options::ENABLE_SMART_PUNCTUATION);
-        options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+        Ok(self.process_document(content, None)?.0)
+    }
+
+    /// Renders a document to HTML, returning it with the configuration after
+    /// the document's front matter has been applied.
+    pub fn process_document(&self, content: &str, base_dir: Option<&Path>) -> Result<(String, Config)> {
+        self.process_document_with_base(content, base_dir, &Config::default())
+    }
+
+    /// Like [`MarkdownProcessor::process_document`], with settings from config
+    /// files beneath the front matter. The processor's own configuration wins
+    /// over both for the settings it was given; see [`Config::merge`].
+    pub fn process_document_with_base(&self, content: &str, base_dir: Option<&Path>, base: &Config) -> Result<(String, Config)> {
+        self.process_document_with_resources(content, base_dir, base, &mut Resources::new())
+    }
 
-        // Parse the markdown and convert to HTML
-        let parser = Parser::new_ext(content, options);
-        let mut html_output = String::new();
-        html::push_html(&mut html_output, parser);
+    /// Like [`MarkdownProcessor::process_document_with_base`], reading
+    /// stylesheets through `resources`.
+    pub fn process_document_with_resources(
+        &self,
+        content: &str,
+        base_dir: Option<&Path>,
+        base: &Config,
+        resources: &mut Resources,
+    ) -> Result<(String, Config)> {
+        let (front_matter, content) = FrontMatter::split(content)?;
+        let mut config = base.clone();
+        front_matter.apply_to(&mut config, base_dir)?;
+        config.merge(&self.config)?;
+        // The document information is available to templates too
+        let mut variables = config.variables.clone();
+        for (name, value) in [("title", &config.title), ("author", &config.author), ("subject", &config.subject)] {
+            if let Some(value) = value {
+                variables.entry(name.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+        let content = expand_templates(content, &variables, parser_options(&config))?;
+        let document_styles = front_matter.stylesheets.into_vec().into_iter().map(|path| match base_dir {
+            Some(dir) => dir.join(path),
+            None => path,
+        });
+        let html = render(&content, &config, base_dir, document_styles.collect(), front_matter.css, resources)?;
+        Ok((html, config))
+    }
+}
 
-        // Create the final HTML with styles
-        Ok(format!(
-            r#"<!DOCTYPE html>
+impl Default for MarkdownProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The theme and user styles come after the built-in sheet so they win
+// ties, and the document's own styles come after those from the configuration
+fn render(
+    content: &str,
+    config: &Config,
+    base_dir: Option<&Path>,
+    document_sheets: Vec<PathBuf>,
+    document_css: Option<String>,
+    resources: &mut Resources,
+) -> Result<String> {
+    let mut user_styles = vec![config.theme.stylesheet().to_string()];
+    for path in &config.stylesheets {
+        user_styles.push(resources.stylesheet_file(path)?);
+    }
+    user_styles.extend(config.custom_css.clone());
+    for path in &document_sheets {
+        user_styles.push(resources.stylesheet_file(path)?);
+    }
+    user_styles.extend(document_css);
+    let user_styles: String = user_styles
+        .iter()
+        .filter(|css| !css.is_empty())
+        .map(|css| format!("<style>\n{}\n</style>\n", escape_style(css)))
+        .collect();
+
+    // Parse the markdown and convert to HTML
+    let mut in_code_block = false;
+    let parser = Parser::new_ext(content, parser_options(config)).map(|event| match event {
+        Event::Start(Tag::CodeBlock(_)) => {
+            in_code_block = true;
+            event
+        }
+        Event::End(TagEnd::CodeBlock) => {
+            in_code_block = false;
+            event
+        }
+        Event::Text(text) if !in_code_block && text.contains(':') => {
+            Event::Text(CowStr::from(expand_shortcodes(&text)))
+        }
+        _ => event,
+    });
+    let mut html_output = String::new();
+    html::push_html(&mut html_output, parser);
+    // Images are found relative to the document, as with a web page's base URL
+    let base = match base_dir.filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => format!("<base href=\"{}/\">\n", dir.display().to_string().replace('&', "&amp;").replace('"', "&quot;")),
+        None => String::new(),
+    };
+    let builtin_styles = escape_style(&config.custom_theme.clone().unwrap_or_default().to_css(config.font_size));
+
+    // Create the final HTML with styles
+    Ok(format!(
+        r#"<!DOCTYPE html>
 <html>
 <head>
 <meta charset="utf-8">
-<style>
-* {{ box-sizing: border-box; }}
-body {{ font-family: system-ui, -apple-system, 'Segoe UI', 'Roboto', 'Helvetica Neue', sans-serif; line-height: 1.6; font-size: 11pt; color: #111; margin: 0; padding: 1em; }}
-h1, h2, h3, h4, h5, h6 {{ margin: 0; padding: 0.5em 0 0.25em; line-height: 1.2; }}
-h1 {{ font-size: 2em; }}
-h2 {{ font-size: 1.5em; }}
-h3 {{ font-size: 1.17em; }}
-h4 {{ font-size: 1em; }}
-h5 {{ font-size: 0.83em; }}
-h6 {{ font-size: 0.67em; }}
-p {{ margin: 0 0 0.75em; }}
-blockquote {{ margin: 0.5em 0; padding-left: 1em; border-left: 4px solid #dcdcdc; color: #666; }}
-ul, ol {{ margin: 0 0 0.75em; padding-left: 2em; }}
-li {{ margin: 0.25em 0; }}
-pre {{ margin: 0.75em 0; padding: 1em; background-color: #f8f8f8; border-radius: 4px; overflow-x: auto; }}
-code {{ background-color: #f8f8f8; padding: 0.2em 0.4em; border-radius: 3px; font-family: 'SFMono-Regular', Consolas, 'Liberation Mono', Menlo, monospace; font-size: 0.9em; }}
-pre code {{ padding: 0; background: none; }}
-img {{ max-width: 100%; height: auto; margin: 0.75em 0; }}
-table {{ border-spacing: 0; border-collapse: collapse; margin: 0.75em 0; width: 100%; }}
-table th, table td {{ padding: 0.5em; border: 1px solid #dcdcdc; }}
-table th {{ font-weight: 600; background-color: #f8f8f8; }}
-table tr:nth-child(2n) {{ background-color: #f8f8f8; }}
-hr {{ margin: 1.5em 0; border: 0; border-top: 1px solid #dcdcdc; }}
-.page-break {{ page-break-after: always; }}
-</style>
-</head>
+{base}<style>
+{builtin_styles}</style>
+{user_styles}</head>
 <body>
 {html_output}
 </body>
 </html>"#
-        ))
+    ))
+}
+
+// Set up options for GitHub-flavored Markdown
+fn parser_options(config: &Config) -> Options {
+    let mut options = Options::empty();
+    if config.enable_gfm {
+        options.insert(Options::ENABLE_STRIKETHROUGH);
+        options.insert(Options::ENABLE_TABLES);
+        options.insert(Options::ENABLE_FOOTNOTES);
+        options.insert(Options::ENABLE_TASKLISTS);
+    }
+    options.insert(Options::ENABLE_SMART_PUNCTUATION);
+    options.insert(Options::ENABLE_HEADING_ATTRIBUTES);
+    options
+}
+
+// Templates are expanded in the Markdown source, so a value can contain
+// Markdown, but code spans and blocks are copied as written
+fn expand_templates(content: &str, variables: &BTreeMap<String, String>, options: Options) -> Result<String> {
+    if !content.contains("{{") {
+        return Ok(content.to_string());
+    }
+    let mut output = String::with_capacity(content.len());
+    let mut expanded = 0;
+    for (event, range) in Parser::new_ext(content, options).into_offset_iter() {
+        if matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))) && range.start >= expanded {
+            output.push_str(&template::expand(&content[expanded..range.start], variables)?);
+            output.push_str(&content[range.clone()]);
+            expanded = range.end;
+        }
+    }
+    output.push_str(&template::expand(&content[expanded..], variables)?);
+    Ok(output)
+}
+
+// `</style` in the CSS would close the element early. In CSS `\/` is just
+// `/`, so escaping the slash keeps its meaning
+fn escape_style(css: &str) -> String {
+    let mut escaped = String::with_capacity(css.len());
+    let mut copied = 0;
+    for (start, _) in css.to_ascii_lowercase().match_indices("</style") {
+        escaped.push_str(&css[copied..start + 1]);
+        escaped.push('\\');
+        copied = start + 1;
+    }
+    escaped.push_str(&css[copied..]);
+    escaped
+}
+
+/// Replaces GitHub-style emoji shortcodes such as `:rocket:` with the
+/// emoji itself. Unknown shortcodes are left as written.
+pub fn expand_shortcodes(text: &str) -> String {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-')))
+            .unwrap_or(after.len());
+        let emoji = (name_len > 0 && after[name_len..].starts_with(':'))
+            .then(|| emojis::get_by_shortcode(&after[..name_len]))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                output.push_str(emoji.as_ref());
+                rest = &after[name_len + 1..];
+            }
+            None => {
+                output.push(':');
+                rest = after;
+            }
+        }
     }
+    output.push_str(rest);
+    output
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::theme::NamedTheme;
 
     #[test]
     fn test_basic_markdown_conversion() {
@@ -83,4 +239,104 @@ mod tests {
         assert!(html.contains("<h1>Hello World</h1>"));
         assert!(html.contains("<p>This is a test.</p>"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_emoji_shortcodes_outside_code() {
+        let processor = MarkdownProcessor::new();
+        let html = processor
+            .process_content("Launch :rocket: at 10:30 :not_an_emoji:\n\n```\n:rocket:\n```")
+            .unwrap();
+        assert!(html.contains("<p>Launch 🚀 at 10:30 :not_an_emoji:</p>"));
+        assert!(html.contains("<code>:rocket:\n</code>"));
+    }
+
+    #[test]
+    fn test_shortcodes_with_underscores_and_signs() {
+        assert_eq!(expand_shortcodes(":+1: :-1: :white_check_mark:"), "👍 👎 ✅");
+        assert_eq!(expand_shortcodes("a:b_c:d and :heavy_plus_sign::x:"), "a:b_c:d and ➕❌");
+        let html = MarkdownProcessor::new().process_content("Done :white_check_mark: :+1:").unwrap();
+        assert!(html.contains("<p>Done ✅ 👍</p>"));
+    }
+
+    #[test]
+    fn test_gfm_extensions_follow_config() {
+        let markdown = "~~gone~~\n\n| a |\n|---|\n| 1 |";
+        let gfm = MarkdownProcessor::new().process_content(markdown).unwrap();
+        assert!(gfm.contains("<del>gone</del>"));
+        assert!(gfm.contains("<table>"));
+
+        let plain = MarkdownProcessor::from_config(&Config::new().with_gfm(false))
+            .process_content(markdown)
+            .unwrap();
+        assert!(!plain.contains("<del>"));
+        assert!(!plain.contains("<table>"));
+    }
+
+    #[test]
+    fn test_user_stylesheets_follow_builtin_styles() {
+        let dir = tempfile::tempdir().unwrap();
+        let sheet = dir.path().join("theme.css");
+        fs::write(&sheet, "h1 { color: navy; background: url(bg.png) }").unwrap();
+        let document = dir.path().join("doc.md");
+        fs::write(&document, "---\nstylesheet: theme.css\ncss: \"p { color: red }\"\n---\n# Title\n").unwrap();
+
+        let processor = MarkdownProcessor::from_config(&Config::new().with_custom_css("h1 { color: green }"));
+        let html = processor.process_file(&document).unwrap();
+        let builtin = html.find("h1 { font-size: 2em;").unwrap();
+        let green = html.find("color: green").unwrap();
+        let navy = html.find("color: navy").unwrap();
+        assert!(builtin < green && green < navy && navy < html.find("color: red").unwrap());
+        let expected = dir.path().canonicalize().unwrap().join("bg.png");
+        assert!(html.contains(&format!("url(\"{}\")", expected.display())));
+        assert!(html.contains("<h1>Title</h1>") && !html.contains("stylesheet:"));
+
+        let missing = MarkdownProcessor::from_config(&Config::new().with_stylesheet(dir.path().join("nope.css")));
+        assert!(matches!(missing.process_content("x"), Err(Mark2PdfError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_front_matter_theme_overrides_config_files() {
+        let files = Config::new().with_theme(NamedTheme::Github);
+        let processor = MarkdownProcessor::new();
+        let (github, _) = processor.process_document_with_base("# Title", None, &files).unwrap();
+        assert!(github.contains(NamedTheme::Github.stylesheet()));
+        let front_matter = "---\ntheme: academic\n---\n# Title";
+        let (academic, _) = processor.process_document_with_base(front_matter, None, &files).unwrap();
+        assert!(academic.contains(NamedTheme::Academic.stylesheet()));
+        assert!(!academic.contains(NamedTheme::Github.stylesheet()));
+
+        // Explicit options win over the document
+        let explicit = MarkdownProcessor::from_config(&Config::new().with_theme(NamedTheme::Minimal));
+        let (minimal, config) = explicit.process_document_with_base(front_matter, None, &files).unwrap();
+        assert!(minimal.contains(NamedTheme::Minimal.stylesheet()));
+        assert_eq!(config.theme, NamedTheme::Minimal);
+    }
+
+    #[test]
+    fn test_template_variables_from_config_and_front_matter() {
+        let processor = MarkdownProcessor::from_config(&Config::new().with_variable("product", "Widget"));
+        let markdown = "---\ntitle: Release notes\nvariables:\n  version: 2.1\n  product: Gadget\n---\n# {{ title }}\n\n{{ product }} {{ version }}";
+        let html = processor.process_content(markdown).unwrap();
+        assert!(html.contains("<h1>Release notes</h1>"));
+        // Explicit variables win over the document's own
+        assert!(html.contains("<p>Widget 2.1</p>"));
+    }
+
+    #[test]
+    fn test_templates_are_not_expanded_in_code() {
+        let processor = MarkdownProcessor::from_config(&Config::new().with_variable("name", "World"));
+        let markdown = "Hello {{ name }}, or `{{name}}` in Handlebars.\n\n```yaml\nenv:\n  TOKEN: ${{ secrets.TOKEN }}\n```\n\n    {{> partial }}\n";
+        let html = processor.process_content(markdown).unwrap();
+        assert!(html.contains("<p>Hello World, or <code>{{name}}</code> in Handlebars.</p>"));
+        assert!(html.contains("TOKEN: ${{ secrets.TOKEN }}"));
+        assert!(html.contains("{{&gt; partial }}"));
+    }
+
+    #[test]
+    fn test_css_cannot_close_the_style_element() {
+        let css = "p::after { content: \"</STYLE><h1>Injected</h1>\" }";
+        let html = MarkdownProcessor::from_config(&Config::new().with_custom_css(css)).process_content("text").unwrap();
+        assert!(html.contains("content: \"<\\/STYLE><h1>Injected</h1>\""));
+        assert_eq!(html.matches("</style>").count(), html.matches("<style>").count());
+    }
+}
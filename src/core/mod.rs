@@ -1,2 +1,14 @@
+pub mod color;
+pub mod css;
+pub mod emoji;
+pub mod font_db;
+pub mod fonts;
+pub mod front_matter;
+pub mod layout;
 pub mod markdown;
 pub mod pdf;
+pub mod resources;
+pub mod shaping;
+pub mod style;
+pub mod template;
+pub mod theme;
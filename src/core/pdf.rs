@@ -1,86 +1,525 @@
+use crate::config::{Config, Margins, Watermark, WatermarkContent};
+use crate::core::color::{parse_color, Rgba};
+use crate::core::css::{self, Stylesheet};
+use crate::core::emoji::{EmojiRenderer, EmojiSource};
+use crate::core::font_db::{parse_family_list, FontDatabase};
+use crate::core::fonts::{pt_to_mm, FontChain, FontFamily, FontRun, FontSource, FontStyle, RunFont};
+use crate::core::layout::{base_level, break_lines, break_opportunities, is_cjk, visual_pieces, Fragment, Paragraph};
+use crate::core::resources::Resources;
+use crate::core::shaping;
+use crate::core::style::{Border, ComputedStyle, Display, StyleEngine};
 use crate::error::{Mark2PdfError, Result};
 use html5ever::parse_document;
 use html5ever::tendril::TendrilSink;
 use markup5ever_rcdom::{Handle, NodeData, RcDom};
 use printpdf::*;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
-use std::io::BufWriter;
-use std::path::Path;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use unicode_bidi::ParagraphBidiInfo;
 
-#[derive(Debug, Clone)]
+// Body text size when neither the configuration nor a stylesheet sets one
+const DEFAULT_FONT_SIZE: f32 = 11.0;
+// Line box height as a multiple of the font size; CJK text is set looser
+pub const LINE_HEIGHT: f32 = 1.4;
+const CJK_LINE_HEIGHT: f32 = 1.7;
+// Baseline position for lines without any text
+const ASCENT: f32 = 0.8;
+const DESCENT: f32 = 0.25;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TextFormat {
     pub font_size: f32,
-    pub color: (f32, f32, f32),
-    pub background_color: Option<(f32, f32, f32)>,
+    pub color: Rgba,
+    pub background_color: Option<Rgba>,
     pub is_bold: bool,
     pub is_italic: bool,
     pub is_underline: bool,
+    pub is_strikethrough: bool,
+    // Raise above the baseline in points; negative values lower the text
+    pub baseline_shift: f32,
+    // Box drawn around inline content, such as `kbd` keycaps
+    pub border: Option<Border>,
+    // The element the box belongs to, so neighbouring keycaps get a box each
+    pub inline_box: Option<usize>,
     pub alignment: TextAlignment,
+    pub font_family: Option<String>,
+    pub direction: TextDirection,
+    pub preserve_whitespace: bool,
+    // Line box height as a multiple of the font size
+    pub line_height: f32,
+    // Product of the CSS `opacity` of the element and its ancestors
+    pub opacity: f32,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TextAlignment {
+    // Left for left-to-right paragraphs, right for right-to-left ones
+    Start,
     Left,
     Center,
     Right,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextDirection {
+    Ltr,
+    Rtl,
+    // Taken from the first strong character of each paragraph
+    Auto,
+}
+
 impl Default for TextFormat {
     fn default() -> Self {
         Self {
             font_size: 12.0,
-            color: (0.0, 0.0, 0.0),
+            color: (0.0, 0.0, 0.0, 1.0),
             background_color: None,
             is_bold: false,
             is_italic: false,
             is_underline: false,
-            alignment: TextAlignment::Left,
+            is_strikethrough: false,
+            baseline_shift: 0.0,
+            border: None,
+            inline_box: None,
+            alignment: TextAlignment::Start,
+            font_family: None,
+            direction: TextDirection::Ltr,
+            preserve_whitespace: false,
+            line_height: LINE_HEIGHT,
+            opacity: 1.0,
+        }
+    }
+}
+
+// The background and content layers of one page. Block backgrounds are
+// painted on the background layer once the block's height is known.
+struct PageLayers {
+    background: PdfLayerReference,
+    content: PdfLayerReference,
+}
+
+// A block box being laid out. Positions are (page number, y).
+struct BlockBox {
+    // Boxes are numbered in document order so backgrounds stack correctly
+    seq: usize,
+    top: (usize, f32),
+    // Border edges
+    left: f32,
+    right: f32,
+    indents: (f32, f32),
+    style: ComputedStyle,
+}
+
+struct Background {
+    seq: usize,
+    page: usize,
+    rect: (f32, f32, f32, f32),
+    paint: Paint,
+}
+
+enum Paint {
+    Color(Rgba),
+    // An image and its opacity
+    Image(Arc<::image::DynamicImage>, f32),
+}
+
+/// Constant-alpha graphics states used on each page, in 1/255 steps.
+/// printpdf cannot add ExtGState resources to a page, so they are added to
+/// the document after it is written.
+#[derive(Default)]
+struct AlphaStates {
+    used: BTreeSet<(usize, u8)>,
+}
+
+impl AlphaStates {
+    // Switches the layer to a state that draws fills and strokes with `alpha`.
+    // Returns whether anything would be visible; `end` must follow either way.
+    fn begin(&mut self, layer: &PdfLayerReference, page: usize, alpha: f32) -> bool {
+        let level = alpha_level(alpha);
+        if level < 255 {
+            layer.save_graphics_state();
+            if level > 0 {
+                self.used.insert((page, level));
+                layer.add_operation(lopdf::content::Operation::new(
+                    "gs",
+                    vec![lopdf::Object::Name(alpha_state_name(level).into_bytes())],
+                ));
+            }
+        }
+        level > 0
+    }
+
+    fn end(&self, layer: &PdfLayerReference, alpha: f32) {
+        if alpha_level(alpha) < 255 {
+            layer.restore_graphics_state();
+        }
+    }
+
+    // Adds the states to the resources of the pages that use them
+    fn add_to(&self, pdf: &mut lopdf::Document) -> Result<()> {
+        let pages: Vec<lopdf::ObjectId> = pdf.get_pages().into_values().collect();
+        for (index, &page_id) in pages.iter().enumerate() {
+            let mut states = lopdf::Dictionary::new();
+            for &(_, level) in self.used.range((index, 0)..=(index, u8::MAX)) {
+                let alpha = level as f32 / 255.0;
+                let state = lopdf::Dictionary::from_iter(vec![
+                    ("Type", lopdf::Object::Name(b"ExtGState".to_vec())),
+                    ("ca", lopdf::Object::Real(alpha)),
+                    ("CA", lopdf::Object::Real(alpha)),
+                ]);
+                states.set(alpha_state_name(level), lopdf::Object::Dictionary(state));
+            }
+            if !states.is_empty() {
+                add_resources(page_resources(pdf, page_id)?, "ExtGState", states);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Image XObjects drawn on each page. printpdf writes soft masks inline,
+/// which readers reject, so images are also added to the document after it
+/// is written. Each image is stored once however many pages show it.
+#[derive(Default)]
+struct ImageXObjects {
+    images: Vec<Arc<::image::DynamicImage>>,
+    used: BTreeSet<(usize, usize)>,
+}
+
+impl ImageXObjects {
+    // Draws the image into the rectangle with its bottom left corner at (x, y)
+    fn draw(&mut self, layer: &PdfLayerReference, page: usize, image: &Arc<::image::DynamicImage>, (x, y, width, height): (f32, f32, f32, f32)) {
+        let id = match self.images.iter().position(|known| Arc::ptr_eq(known, image)) {
+            Some(id) => id,
+            None => {
+                self.images.push(image.clone());
+                self.images.len() - 1
+            }
+        };
+        self.used.insert((page, id));
+        layer.save_graphics_state();
+        let [x, y, width, height] = [x, y, width, height].map(|mm| Pt::from(Mm(mm)).0);
+        layer.set_ctm(CurTransMat::Raw([width, 0.0, 0.0, height, x, y]));
+        layer.add_operation(lopdf::content::Operation::new(
+            "Do",
+            vec![lopdf::Object::Name(image_name(id).into_bytes())],
+        ));
+        layer.restore_graphics_state();
+    }
+
+    fn add_to(&self, pdf: &mut lopdf::Document) -> Result<()> {
+        let ids: Vec<lopdf::ObjectId> = self.images.iter().map(|image| add_image_stream(pdf, image)).collect();
+        let pages: Vec<lopdf::ObjectId> = pdf.get_pages().into_values().collect();
+        for (index, &page_id) in pages.iter().enumerate() {
+            let mut xobjects = lopdf::Dictionary::new();
+            for &(_, id) in self.used.range((index, 0)..=(index, usize::MAX)) {
+                xobjects.set(image_name(id), lopdf::Object::Reference(ids[id]));
+            }
+            if xobjects.is_empty() {
+                continue;
+            }
+            add_resources(page_resources(pdf, page_id)?, "XObject", xobjects);
+        }
+        Ok(())
+    }
+}
+
+fn image_name(id: usize) -> String {
+    format!("Image{}", id)
+}
+
+// Writes the colour channels as a compressed RGB image, with the alpha
+// channel, if any, as a separate greyscale soft mask
+fn add_image_stream(pdf: &mut lopdf::Document, image: &::image::DynamicImage) -> lopdf::ObjectId {
+    let image_dict = |color_space: &str| {
+        lopdf::Dictionary::from_iter(vec![
+            ("Type", lopdf::Object::Name(b"XObject".to_vec())),
+            ("Subtype", lopdf::Object::Name(b"Image".to_vec())),
+            ("Width", lopdf::Object::Integer(image.width() as i64)),
+            ("Height", lopdf::Object::Integer(image.height() as i64)),
+            ("ColorSpace", lopdf::Object::Name(color_space.as_bytes().to_vec())),
+            ("BitsPerComponent", lopdf::Object::Integer(8)),
+        ])
+    };
+    let compressed = |dict, data| {
+        let mut stream = lopdf::Stream::new(dict, data);
+        // Left uncompressed if flate fails, which is still valid
+        let _ = stream.compress();
+        stream
+    };
+    let mut dict = image_dict("DeviceRGB");
+    if image.color().has_alpha() {
+        let alpha: Vec<u8> = image.to_rgba8().pixels().map(|pixel| pixel[3]).collect();
+        let mask = pdf.add_object(compressed(image_dict("DeviceGray"), alpha));
+        dict.set("SMask", lopdf::Object::Reference(mask));
+    }
+    pdf.add_object(compressed(dict, image.to_rgb8().into_raw()))
+}
+
+// The page's resource dictionary, added if it was missing
+fn page_resources(pdf: &mut lopdf::Document, page_id: lopdf::ObjectId) -> Result<&mut lopdf::Dictionary> {
+    let pdf_error = |e: lopdf::Error| Mark2PdfError::PdfError(e.to_string());
+    let page = pdf.get_dictionary(page_id).map_err(pdf_error)?;
+    let resources = match page.get(b"Resources") {
+        Ok(lopdf::Object::Reference(id)) => Some(*id),
+        Ok(lopdf::Object::Dictionary(_)) => None,
+        _ => {
+            let page = pdf.get_dictionary_mut(page_id).map_err(pdf_error)?;
+            page.set("Resources", lopdf::Object::Dictionary(lopdf::Dictionary::new()));
+            None
+        }
+    };
+    match resources {
+        Some(id) => pdf.get_dictionary_mut(id).map_err(pdf_error),
+        None => {
+            let page = pdf.get_dictionary_mut(page_id).map_err(pdf_error)?;
+            page.get_mut(b"Resources").and_then(lopdf::Object::as_dict_mut).map_err(pdf_error)
         }
     }
 }
 
+// Adds to a category of named resources, such as XObject, keeping the entries
+// already there. `Dictionary::extend` would drop them
+fn add_resources(resources: &mut lopdf::Dictionary, category: &str, entries: lopdf::Dictionary) {
+    if let Ok(lopdf::Object::Dictionary(existing)) = resources.get_mut(category.as_bytes()) {
+        for (name, entry) in entries.iter() {
+            existing.set(name.clone(), entry.clone());
+        }
+    } else {
+        resources.set(category, lopdf::Object::Dictionary(entries));
+    }
+}
+
+fn alpha_level(alpha: f32) -> u8 {
+    (alpha.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+fn alpha_state_name(level: u8) -> String {
+    format!("Alpha{}", level)
+}
+
+// Space between an inline box and its text on either side
+fn inline_box_padding(format: &TextFormat) -> f32 {
+    match format.border {
+        Some(_) => pt_to_mm(format.font_size) * 0.1,
+        None => 0.0,
+    }
+}
+
+// Applies CSS `opacity` to a colour's own alpha
+fn faded(color: Rgba, opacity: f32) -> Rgba {
+    (color.0, color.1, color.2, color.3 * opacity)
+}
+
 pub struct PdfState {
     doc: PdfDocumentReference,
-    current_page: PdfPageIndex,
+    pages: Vec<PageLayers>,
+    page_number: usize,
     current_layer: PdfLayerReference,
     current_y: f32,
-    margin: f32,
+    margins: Margins,
     page_height: f32,
     page_width: f32,
     format_stack: Vec<TextFormat>,
+    fonts: FontChain,
+    // Installed fonts are scanned lazily, the first time a font family is
+    // requested by name
+    resources: Resources,
+    // From `<base href>`; relative image paths are found in it
+    base_dir: Option<PathBuf>,
+    font_dirs: Vec<PathBuf>,
+    resolved_families: HashMap<String, Vec<usize>>,
+    // Installed emoji fonts are only looked up once emoji show up
+    emoji_searched: bool,
+    // Inline content waiting for the end of its block
+    inline: Vec<Fragment>,
+    indent_left: f32,
+    indent_right: f32,
+    // The next item number of each open list
+    list_stack: Vec<usize>,
+    pending_marker: Option<String>,
+    // Bottom margin of the previous block, collapsed with the next top margin
+    pending_margin: f32,
+    block_count: usize,
+    inline_box_count: usize,
+    backgrounds: Vec<Background>,
+    alpha_states: AlphaStates,
+    images: ImageXObjects,
+    // Filled behind every page; the configured colour wins over one
+    // propagated from the html or body element
+    page_background: Option<Rgba>,
+    canvas_background: Option<Rgba>,
+    page_background_image: Option<Arc<::image::DynamicImage>>,
+    watermark: Option<Watermark>,
+    watermark_image: Option<Arc<::image::DynamicImage>>,
+    header: Option<String>,
+    footer: Option<String>,
 }
 
 impl PdfState {
     pub fn new() -> Self {
+        // A4 with 20mm margins
+        Self::with_page(210.0, 297.0, Margins::uniform(20.0))
+    }
+
+    pub fn with_page(page_width: f32, page_height: f32, margins: Margins) -> Self {
         let (doc, page_idx, layer_idx) = PdfDocument::new(
             "PDF Document",
-            Mm(210.0),  // A4 width
-            Mm(297.0),  // A4 height
-            "Layer 1",
+            Mm(page_width),
+            Mm(page_height),
+            "Background",
         );
         let page = doc.get_page(page_idx);
-        let current_layer = page.get_layer(layer_idx);
-        
+        let background = page.get_layer(layer_idx);
+        let current_layer = page.add_layer("Content");
+
         Self {
             doc,
-            current_page: page_idx,
+            pages: vec![PageLayers {
+                background,
+                content: current_layer.clone(),
+            }],
+            page_number: 0,
             current_layer,
-            current_y: 277.0,  // Start near the top with some margin
-            margin: 20.0,
-            page_height: 297.0,
-            page_width: 210.0,
+            current_y: page_height - margins.top,  // Start below the top margin
+            margins,
+            page_height,
+            page_width,
             format_stack: vec![TextFormat {
-                font_size: 11.0,  // 11pt base font size
-                color: (0.067, 0.067, 0.067),  // #111111
+                font_size: DEFAULT_FONT_SIZE,
+                color: (0.067, 0.067, 0.067, 1.0), // #111111
                 background_color: None,
                 is_bold: false,
                 is_italic: false,
                 is_underline: false,
-                alignment: TextAlignment::Left,
+                is_strikethrough: false,
+                baseline_shift: 0.0,
+                border: None,
+                inline_box: None,
+                alignment: TextAlignment::Start,
+                font_family: None,
+                direction: TextDirection::Ltr,
+                preserve_whitespace: false,
+                line_height: LINE_HEIGHT,
+                opacity: 1.0,
             }],
+            fonts: FontChain::builtin(),
+            emoji_searched: false,
+            resources: Resources::new(),
+            base_dir: None,
+            font_dirs: Vec::new(),
+            resolved_families: HashMap::new(),
+            inline: Vec::new(),
+            indent_left: 0.0,
+            indent_right: 0.0,
+            list_stack: Vec::new(),
+            pending_marker: None,
+            pending_margin: 0.0,
+            block_count: 0,
+            inline_box_count: 0,
+            backgrounds: Vec::new(),
+            alpha_states: AlphaStates::default(),
+            images: ImageXObjects::default(),
+            page_background: None,
+            canvas_background: None,
+            page_background_image: None,
+            watermark: None,
+            watermark_image: None,
+            header: None,
+            footer: None,
         }
     }
 
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let (page_width, page_height) = config.page_size();
+        let mut state = Self::with_page(page_width, page_height, config.margins);
+        state.configure(config)?;
+        Ok(state.with_metadata(config))
+    }
+
+    fn configure(&mut self, config: &Config) -> Result<()> {
+        self.format_stack[0].font_size = config.font_size;
+        self.font_dirs = config.font_dirs.clone();
+        for path in &config.fallback_fonts {
+            let face = self.resources.font(path)?;
+            self.fonts.push(FontFamily::from_face(face));
+        }
+        if let Some(list) = &config.font_family {
+            let families = self.font_db().resolve_list(list)?;
+            for family in families.into_iter().rev() {
+                self.fonts.push_front(family);
+            }
+        }
+        if let Some(dir) = &config.emoji_images {
+            self.fonts.set_emoji(EmojiRenderer::new(EmojiSource::Images(dir.clone())));
+        } else if let Some(path) = &config.emoji_font {
+            let face = self.resources.font(path)?;
+            self.fonts.set_emoji(EmojiRenderer::new(EmojiSource::Font(face)));
+        }
+        self.page_background = config.page_background.as_deref().and_then(parse_color);
+        if let Some(path) = &config.page_background_image {
+            self.page_background_image = Some(self.resources.image(path)?);
+        }
+        if let Some(watermark) = &config.watermark {
+            if let WatermarkContent::Image(path) = &watermark.content {
+                self.watermark_image = Some(self.resources.image(path)?);
+            }
+            self.watermark = Some(watermark.clone());
+        }
+        self.header = config.header.clone();
+        self.footer = config.footer.clone();
+        Ok(())
+    }
+
+    // The document builders take it by value
+    fn with_metadata(mut self, config: &Config) -> Self {
+        if let Some(title) = &config.title {
+            self.doc = self.doc.with_title(title.as_str());
+        }
+        if let Some(author) = &config.author {
+            self.doc = self.doc.with_author(author.as_str());
+        }
+        if let Some(subject) = &config.subject {
+            self.doc = self.doc.with_subject(subject.as_str());
+        }
+        if !config.keywords.is_empty() {
+            self.doc = self.doc.with_keywords(config.keywords.clone());
+        }
+        self
+    }
+
+    fn font_db(&mut self) -> &mut FontDatabase {
+        self.resources.font_db(&self.font_dirs)
+    }
+
+    // Indices into the font chain for a CSS `font-family` list. The generic
+    // sans-serif and monospace families map to the PDF base fonts, which
+    // need no embedding.
+    fn preferred_families(&mut self, list: &str) -> Result<Vec<usize>> {
+        if let Some(indices) = self.resolved_families.get(list) {
+            return Ok(indices.clone());
+        }
+        let mut indices = Vec::new();
+        for name in parse_family_list(list) {
+            let index = match name.to_ascii_lowercase().as_str() {
+                "sans-serif" => Some(self.fonts.register(FontFamily::helvetica())),
+                "monospace" => Some(self.fonts.register(FontFamily::courier())),
+                _ => self.font_db().resolve_family(&name)?.map(|family| self.fonts.register(family)),
+            };
+            match index {
+                Some(index) if !indices.contains(&index) => indices.push(index),
+                Some(_) => {}
+                // Stylesheets routinely list fonts for other platforms
+                None => log::debug!("Font family '{}' is not installed", name),
+            }
+        }
+        self.resolved_families.insert(list.to_string(), indices.clone());
+        Ok(indices)
+    }
+
     fn current_format(&self) -> TextFormat {
         self.format_stack.last().unwrap().clone()
     }
@@ -95,418 +534,1067 @@ impl PdfState {
         }
     }
 
-    fn write_text(&mut self, text: &str) -> Result<()> {
+    fn push_text(&mut self, text: &str) {
         let format = self.current_format();
-        let font = if format.is_bold && format.is_italic {
-            self.doc.add_builtin_font(BuiltinFont::HelveticaBoldOblique)?
-        } else if format.is_bold {
-            self.doc.add_builtin_font(BuiltinFont::HelveticaBold)?
-        } else if format.is_italic {
-            self.doc.add_builtin_font(BuiltinFont::HelveticaOblique)?
+        let text = if format.preserve_whitespace {
+            text.replace('\t', "    ")
         } else {
-            self.doc.add_builtin_font(BuiltinFont::Helvetica)?
+            // Collapse whitespace like HTML, dropping it at the start of a
+            // line and where a source line break falls between CJK characters
+            let mut collapsed = String::with_capacity(text.len());
+            let mut previous_space = self
+                .inline
+                .last()
+                .is_none_or(|fragment| fragment.text.ends_with([' ', '\n']));
+            let mut previous_char = self.inline.last().and_then(|fragment| fragment.text.chars().next_back());
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                if c.is_whitespace() {
+                    let mut newline = c == '\n';
+                    while let Some(&next) = chars.peek().filter(|next| next.is_whitespace()) {
+                        newline |= next == '\n';
+                        chars.next();
+                    }
+                    let joins_cjk = newline
+                        && previous_char.is_some_and(is_cjk)
+                        && chars.peek().is_some_and(|&next| is_cjk(next));
+                    if !previous_space && !joins_cjk {
+                        collapsed.push(' ');
+                        previous_space = true;
+                    }
+                } else {
+                    collapsed.push(c);
+                    previous_space = false;
+                    previous_char = Some(c);
+                }
+            }
+            collapsed
         };
+        if !text.is_empty() {
+            self.inline.push(Fragment { text, format });
+        }
+    }
 
-        // Calculate text width (approximate)
-        let text_width = text.len() as f32 * format.font_size * 0.5;
-        let x = match format.alignment {
-            TextAlignment::Left => self.margin,
-            TextAlignment::Center => (self.page_width - text_width) / 2.0,
-            TextAlignment::Right => self.page_width - self.margin - text_width,
+    fn push_line_break(&mut self) {
+        let format = self.current_format();
+        self.inline.push(Fragment {
+            text: "\n".to_string(),
+            format,
+        });
+    }
+
+    // Lays out the buffered inline content using the current block's format
+    fn flush_inline(&mut self) -> Result<()> {
+        let fragments = std::mem::take(&mut self.inline);
+        if fragments.iter().all(|fragment| fragment.text.trim().is_empty()) {
+            return Ok(());
+        }
+        let block = self.current_format();
+        for paragraph in Paragraph::split_hard_breaks(fragments) {
+            self.layout_paragraph(&paragraph, &block)?;
+        }
+        Ok(())
+    }
+
+    fn line_bounds(&self) -> (f32, f32) {
+        (
+            self.margins.left + self.indent_left,
+            self.page_width - self.margins.right - self.indent_right,
+        )
+    }
+
+    fn shape_piece(&mut self, text: &str, format: &TextFormat, rtl: bool) -> Result<Vec<FontRun>> {
+        let style = FontStyle::from_flags(format.is_bold, format.is_italic);
+        let preferred = match &format.font_family {
+            Some(list) => self.preferred_families(list)?,
+            None => Vec::new(),
         };
+        if !self.emoji_searched && !self.fonts.has_emoji() && text.chars().any(|c| c as u32 >= 0x1F000) {
+            self.emoji_searched = true;
+            if let Some(face) = self.font_db().emoji_face()? {
+                self.fonts.set_emoji(EmojiRenderer::new(EmojiSource::Font(face)));
+            }
+        }
+        Ok(self.fonts.split_runs_with(text, style, &preferred, rtl))
+    }
 
-        // Draw background if specified
-        if let Some(bg_color) = format.background_color {
-            let points = vec![
-                (Point::new(Mm(x - 2.0), Mm(self.current_y + 4.0)), false),
-                (Point::new(Mm(x + text_width + 2.0), Mm(self.current_y + 4.0)), false),
-                (Point::new(Mm(x + text_width + 2.0), Mm(self.current_y - format.font_size * 0.35)), false),
-                (Point::new(Mm(x - 2.0), Mm(self.current_y - format.font_size * 0.35)), false),
-            ];
-            let line = Line {
-                points,
-                is_closed: true,
-            };
-            self.current_layer.set_fill_color(Color::Rgb(Rgb::new(bg_color.0, bg_color.1, bg_color.2, None)));
-            self.current_layer.add_line(line);
+    fn measure(&mut self, paragraph: &Paragraph, bidi: &ParagraphBidiInfo, range: std::ops::Range<usize>) -> Result<f32> {
+        let mut width = 0.0;
+        let mut inline_box = None;
+        for (range, index) in paragraph.pieces(range) {
+            let format = &paragraph.fragments[index].format;
+            if format.inline_box != inline_box {
+                inline_box = format.inline_box;
+                width += 2.0 * inline_box_padding(format);
+            }
+            let rtl = bidi.levels[range.start].is_rtl();
+            for run in self.shape_piece(&paragraph.text[range], format, rtl)? {
+                width += run.width_mm(format.font_size);
+            }
+        }
+        Ok(width)
+    }
+
+    fn layout_paragraph(&mut self, paragraph: &Paragraph, block: &TextFormat) -> Result<()> {
+        if paragraph.is_empty() {
+            // Blank line in preformatted text
+            self.commit_margin()?;
+            return self.add_vertical_space(pt_to_mm(block.font_size) * block.line_height);
+        }
+
+        let bidi = ParagraphBidiInfo::new(&paragraph.text, base_level(block.direction));
+        let rtl = bidi.paragraph_level.is_rtl();
+        let (left, right) = self.line_bounds();
+        let opportunities = break_opportunities(&paragraph.text);
+        let lines = break_lines(
+            &paragraph.text,
+            &opportunities,
+            |range| self.measure(paragraph, &bidi, range),
+            right - left,
+        )?;
+
+        for line in lines {
+            let mut items = Vec::new();
+            for piece in visual_pieces(paragraph, &bidi, line) {
+                let format = &paragraph.fragments[piece.fragment].format;
+                for run in self.shape_piece(&paragraph.text[piece.range], format, piece.rtl)? {
+                    items.push((run, format.clone()));
+                }
+            }
+            self.draw_line(&items, block, rtl)?;
+        }
+        Ok(())
+    }
+
+    fn draw_line(&mut self, items: &[(FontRun, TextFormat)], block: &TextFormat, rtl: bool) -> Result<()> {
+        self.commit_margin()?;
+        // The line box fits the tallest font on the line, with the leading
+        // split evenly above and below
+        let (mut font_size, mut ascent, mut descent, mut gap) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+        for (run, format) in items {
+            let shift = pt_to_mm(format.baseline_shift);
+            font_size = font_size.max(format.font_size);
+            ascent = ascent.max(run.ascent_mm(format.font_size) + shift);
+            descent = descent.max(run.descent_mm(format.font_size) - shift);
+            gap = gap.max(run.line_gap_mm(format.font_size));
+        }
+        if items.is_empty() {
+            font_size = block.font_size;
+            ascent = pt_to_mm(font_size) * ASCENT;
+            descent = pt_to_mm(font_size) * DESCENT;
+        }
+        let factor = if items.iter().any(|(run, _)| run.text.chars().any(is_cjk)) {
+            block.line_height.max(CJK_LINE_HEIGHT)
+        } else {
+            block.line_height
+        };
+        let line_height = (pt_to_mm(font_size) * factor).max(ascent + descent + gap);
+        if self.current_y - line_height < self.margins.bottom {
+            self.new_page();
+        }
+        let baseline = self.current_y - (line_height - ascent - descent) / 2.0 - ascent;
+
+        let (left, right) = self.line_bounds();
+        let groups: Vec<_> = items.chunk_by(|a, b| a.1.inline_box == b.1.inline_box).collect();
+        let width: f32 = groups
+            .iter()
+            .map(|group| group.iter().map(|(run, format)| run.width_mm(format.font_size)).sum::<f32>() + 2.0 * inline_box_padding(&group[0].1))
+            .sum();
+        let alignment = match block.alignment {
+            TextAlignment::Start if rtl => TextAlignment::Right,
+            TextAlignment::Start => TextAlignment::Left,
+            alignment => alignment,
+        };
+        let mut x = match alignment {
+            TextAlignment::Center => left + (right - left - width) / 2.0,
+            TextAlignment::Right => right - width,
+            _ => left,
+        };
+
+        if let Some(marker) = self.pending_marker.take() {
+            let mut marker_format = block.clone();
+            marker_format.background_color = None;
+            marker_format.is_underline = false;
+            marker_format.is_strikethrough = false;
+            marker_format.baseline_shift = 0.0;
+            marker_format.border = None;
+            marker_format.inline_box = None;
+            let runs = self.shape_piece(&marker, &marker_format, false)?;
+            let marker_width: f32 = runs.iter().map(|run| run.width_mm(block.font_size)).sum();
+            // Markers hang in the list indentation
+            let mut marker_x = if rtl { right + 1.5 } else { left - marker_width - 1.5 };
+            for run in &runs {
+                self.draw_item(run, &marker_format, marker_x, baseline)?;
+                marker_x += run.width_mm(block.font_size);
+            }
+        }
+
+        // Boxes go behind the text and span all runs of their element on the line
+        for group in groups {
+            let padding = inline_box_padding(&group[0].1);
+            if let Some(border) = group[0].1.border {
+                let width: f32 = group.iter().map(|(run, format)| run.width_mm(format.font_size)).sum();
+                self.draw_inline_box(group, border, x, width + 2.0 * padding, baseline);
+            }
+            x += padding;
+            for (run, format) in group {
+                self.draw_item(run, format, x, baseline)?;
+                x += run.width_mm(format.font_size);
+            }
+            x += padding;
+        }
+        self.current_y -= line_height;
+        Ok(())
+    }
+
+    // A keycap-style box around the runs of one inline element. The box
+    // spans `width` from `x`, padding included
+    fn draw_inline_box(&mut self, group: &[(FontRun, TextFormat)], border: Border, x: f32, width: f32, baseline: f32) {
+        let format = &group[0].1;
+        let baseline = baseline + pt_to_mm(format.baseline_shift);
+        let ascent = group.iter().map(|(run, _)| run.ascent_mm(format.font_size)).fold(0.0, f32::max);
+        let descent = group.iter().map(|(run, _)| run.descent_mm(format.font_size)).fold(0.0, f32::max);
+        let rect = (x, baseline - descent, x + width, baseline + ascent);
+        let layer = self.current_layer.clone();
+        let page = self.page_number;
+        if let Some(background) = format.background_color {
+            fill_rect(&mut self.alpha_states, &layer, page, rect, faded(background, format.opacity));
+        }
+        let color = faded(border.color.unwrap_or(format.color), format.opacity);
+        let (x1, y1, x2, y2) = rect;
+        let inset = border.width / 2.0;
+        let corners = [(x1 + inset, y1 + inset), (x2 - inset, y1 + inset), (x2 - inset, y2 - inset), (x1 + inset, y2 - inset)];
+        for (from, to) in corners.iter().zip(corners.iter().cycle().skip(1)) {
+            stroke_line(&mut self.alpha_states, &layer, page, *from, *to, border.width, color);
+        }
+    }
+
+    fn draw_item(&mut self, run: &FontRun, format: &TextFormat, x: f32, baseline: f32) -> Result<()> {
+        let width = run.width_mm(format.font_size);
+        let size = pt_to_mm(format.font_size);
+        let layer = self.current_layer.clone();
+        let page = self.page_number;
+        let baseline = baseline + pt_to_mm(format.baseline_shift);
+
+        // Boxed content has its background painted with the box
+        if let (Some(bg_color), None) = (format.background_color, format.border) {
+            let top = baseline + run.ascent_mm(format.font_size);
+            let bottom = baseline - run.descent_mm(format.font_size);
+            let color = faded(bg_color, format.opacity);
+            fill_rect(&mut self.alpha_states, &layer, page, (x, bottom, x + width, top), color);
+        }
+
+        if let RunFont::Emoji(image) = &run.font {
+            let bottom = baseline - run.descent_mm(format.font_size);
+            return self.draw_emoji(image, format, x, bottom);
         }
 
         // Set text color and draw text
-        self.current_layer.set_fill_color(Color::Rgb(Rgb::new(
-            format.color.0,
-            format.color.1,
-            format.color.2,
-            None,
-        )));
-        self.current_layer.use_text(text, format.font_size, Mm(x), Mm(self.current_y), &font);
-
-        // Draw underline if needed
-        if format.is_underline {
-            let line = Line {
-                points: vec![
-                    (Point::new(Mm(x), Mm(self.current_y - 1.0)), false),
-                    (Point::new(Mm(x + text_width), Mm(self.current_y - 1.0)), false),
-                ],
-                is_closed: false,
-            };
-            self.current_layer.set_outline_color(Color::Rgb(Rgb::new(
-                format.color.0,
-                format.color.1,
-                format.color.2,
-                None,
-            )));
-            self.current_layer.add_line(line);
+        let alpha = format.color.3 * format.opacity;
+        if self.alpha_states.begin(&layer, page, alpha) {
+            let color = Color::Rgb(Rgb::new(format.color.0, format.color.1, format.color.2, None));
+            layer.set_fill_color(color.clone());
+            self.draw_run(run, format.font_size, x, baseline)?;
+
+            // Draw underline if needed
+            if format.is_underline {
+                let y = baseline - size * 0.12;
+                let line = Line {
+                    points: vec![
+                        (Point::new(Mm(x), Mm(y)), false),
+                        (Point::new(Mm(x + width), Mm(y)), false),
+                    ],
+                    is_closed: false,
+                };
+                layer.set_outline_color(color.clone());
+                layer.set_outline_thickness(format.font_size * 0.06);
+                layer.add_line(line);
+            }
+
+            // Strike through at about half the x-height
+            if format.is_strikethrough {
+                let y = baseline + size * 0.28;
+                layer.set_outline_color(color);
+                layer.set_outline_thickness(format.font_size * 0.06);
+                layer.add_line(Line {
+                    points: vec![
+                        (Point::new(Mm(x), Mm(y)), false),
+                        (Point::new(Mm(x + width), Mm(y)), false),
+                    ],
+                    is_closed: false,
+                });
+            }
         }
+        self.alpha_states.end(&layer, alpha);
 
         Ok(())
     }
 
+    fn draw_run(&mut self, run: &FontRun, font_size: f32, x: f32, y: f32) -> Result<()> {
+        // Emoji are drawn as images by `draw_item`
+        let RunFont::Font(key) = run.font else { return Ok(()) };
+        let font = self.fonts.font_ref(&self.doc, key)?;
+        let face = match self.fonts.source(key) {
+            FontSource::External(face) if !run.glyphs.is_empty() => face.clone(),
+            _ => {
+                self.current_layer.use_text(run.text.as_str(), font_size, Mm(x), Mm(y), &font);
+                return Ok(());
+            }
+        };
+
+        // Shaped text is written as glyph IDs with TJ adjustments, and text
+        // rise for marks the shaper moved vertically
+        self.current_layer.begin_text_section();
+        self.current_layer.set_font(&font, font_size);
+        self.current_layer.set_text_cursor(Mm(x), Mm(y));
+        let mut current_rise = 0.0;
+        for (rise, codepoints) in shaping::to_tj_groups(&face, &run.glyphs) {
+            if rise != current_rise {
+                self.current_layer.set_line_offset(rise * font_size / 1000.0);
+                current_rise = rise;
+            }
+            self.current_layer.write_positioned_codepoints(codepoints);
+        }
+        if current_rise != 0.0 {
+            self.current_layer.set_line_offset(0.0);
+        }
+        self.current_layer.end_text_section();
+        Ok(())
+    }
+
+    // Scales the image so its height matches the font size
+    fn draw_emoji(&mut self, image: &Arc<::image::DynamicImage>, format: &TextFormat, x: f32, bottom: f32) -> Result<()> {
+        let height = pt_to_mm(format.font_size);
+        let width = height * image.width() as f32 / image.height().max(1) as f32;
+        let layer = self.current_layer.clone();
+        if self.alpha_states.begin(&layer, self.page_number, format.opacity) {
+            self.images.draw(&layer, self.page_number, image, (x, bottom, width, height));
+        }
+        self.alpha_states.end(&layer, format.opacity);
+        Ok(())
+    }
+
+    fn new_page(&mut self) {
+        // Table cells that overflowed earlier may have added the page already
+        if self.page_number + 1 < self.pages.len() {
+            self.page_number += 1;
+        } else {
+            let (page_idx, layer_idx) = self.doc.add_page(Mm(self.page_width), Mm(self.page_height), "Background");
+            let page = self.doc.get_page(page_idx);
+            let background = page.get_layer(layer_idx);
+            let content = page.add_layer("Content");
+            self.pages.push(PageLayers { background, content });
+            self.page_number = self.pages.len() - 1;
+        }
+        self.current_layer = self.pages[self.page_number].content.clone();
+        self.current_y = self.page_top();
+    }
+
+    fn page_top(&self) -> f32 {
+        self.page_height - self.margins.top
+    }
+
+    fn at_page_top(&self) -> bool {
+        self.current_y >= self.page_top()
+    }
+
+    fn position(&self) -> (usize, f32) {
+        (self.page_number, self.current_y)
+    }
+
+    fn goto(&mut self, (page, y): (usize, f32)) {
+        self.page_number = page;
+        self.current_layer = self.pages[page].content.clone();
+        self.current_y = y;
+    }
+
     fn add_vertical_space(&mut self, space: f32) -> Result<()> {
         self.current_y -= space;
-        if self.current_y < self.margin {
-            // Create new page in the same document
-            let (page_idx, layer_idx) = self.doc.add_page(Mm(self.page_width), Mm(self.page_height), "Layer 1");
-            let page = self.doc.get_page(page_idx);
-            self.current_layer = page.get_layer(layer_idx);
-            self.current_page = page_idx;
-            self.current_y = self.page_height - self.margin;
+        if self.current_y < self.margins.bottom {
+            self.new_page();
         }
         Ok(())
     }
 
-    fn add_image(&mut self, path: &str) -> Result<()> {
-        let img = ::image::open(path).map_err(|e| Mark2PdfError::ImageError(e.to_string()))?;
-        let dyn_img = img.to_rgba8();
-        let width = dyn_img.width() as f32;
-        let height = dyn_img.height() as f32;
-        
-        // Scale image to fit within margins while maintaining aspect ratio
-        let max_width = self.page_width - 2.0 * self.margin;
-        let scale = if width > max_width {
-            max_width / width
-        } else {
-            1.0
+    // Adds the collapsed margin between the previous block and what follows.
+    // Margins are dropped at the top of a page.
+    fn commit_margin(&mut self) -> Result<()> {
+        let margin = std::mem::take(&mut self.pending_margin);
+        if margin > 0.0 && !self.at_page_top() {
+            self.add_vertical_space(margin)?;
+        }
+        Ok(())
+    }
+
+    fn open_block(&mut self, style: &ComputedStyle) -> Result<BlockBox> {
+        self.flush_inline()?;
+        if style.break_before && !self.at_page_top() {
+            self.new_page();
+            self.pending_margin = 0.0;
+        }
+        self.pending_margin = self.pending_margin.max(style.margin.top);
+        let (border, padding) = (&style.border, &style.padding);
+        let top_edge = border.top.used_width() + padding.top;
+        if top_edge > 0.0 || style.has_background() {
+            self.commit_margin()?;
+        }
+
+        let (left, right) = self.line_bounds();
+        self.block_count += 1;
+        let block = BlockBox {
+            seq: self.block_count,
+            top: self.position(),
+            left: left + style.margin.left,
+            right: right - style.margin.right,
+            indents: (self.indent_left, self.indent_right),
+            style: style.clone(),
         };
-        
-        let final_width = width * scale;
-        let final_height = height * scale;
-        
-        // Center the image horizontally
-        let x = (self.page_width - final_width) / 2.0;
-        
-        // Check if we need a new page
-        if self.current_y - final_height < self.margin {
-            // Create new page in the same document
-            let (page_idx, layer_idx) = self.doc.add_page(Mm(self.page_width), Mm(self.page_height), "Layer 1");
-            let page = self.doc.get_page(page_idx);
-            self.current_layer = page.get_layer(layer_idx);
-            self.current_page = page_idx;
-            self.current_y = self.page_height - self.margin;
-        }
-        
-        let image_file = ImageXObject {
-            width: Px(width as usize),
-            height: Px(height as usize),
-            color_space: ColorSpace::Rgba,
-            bits_per_component: ColorBits::Bit8,
-            interpolate: true,
-            image_data: dyn_img.into_raw(),
-            image_filter: None,
-            clipping_bbox: None,
-            smask: None,
+        self.indent_left += style.margin.left + border.left.used_width() + padding.left;
+        self.indent_right += style.margin.right + border.right.used_width() + padding.right;
+        if top_edge > 0.0 {
+            self.add_vertical_space(top_edge)?;
+        }
+        self.push_format(style.text.clone());
+        Ok(block)
+    }
+
+    // Lays out the rest of the block's content and returns where its bottom border edge is
+    fn finish_block(&mut self, block: &BlockBox) -> Result<(usize, f32)> {
+        self.flush_inline()?;
+        let style = &block.style;
+        let bottom_edge = style.border.bottom.used_width() + style.padding.bottom;
+        if bottom_edge > 0.0 || style.has_background() {
+            self.commit_margin()?;
+        }
+        if bottom_edge > 0.0 {
+            self.add_vertical_space(bottom_edge)?;
+        }
+        Ok(self.position())
+    }
+
+    fn end_block(&mut self, block: &BlockBox) {
+        self.pop_format();
+        (self.indent_left, self.indent_right) = block.indents;
+        if block.style.display == Display::ListItem {
+            self.pending_marker = None;
+        }
+        self.pending_margin = self.pending_margin.max(block.style.margin.bottom);
+        if block.style.break_after {
+            self.new_page();
+            self.pending_margin = 0.0;
+        }
+    }
+
+    fn close_block(&mut self, block: BlockBox) -> Result<()> {
+        let bottom = self.finish_block(&block)?;
+        self.paint_block(&block, bottom)?;
+        self.end_block(&block);
+        Ok(())
+    }
+
+    // Queues the backgrounds and draws the borders of a block, one piece per page it spans
+    fn paint_block(&mut self, block: &BlockBox, bottom: (usize, f32)) -> Result<()> {
+        let style = &block.style;
+        let border = &style.border;
+        let image = match &style.background_image {
+            Some(path) => Some(self.resources.image(&self.resolve(path))?),
+            None => None,
         };
-        
-        let image = Image::from(image_file);
-        image.add_to_layer(
-            self.current_layer.clone(),
-            ImageTransform {
-                translate_x: Some(Mm(x)),
-                translate_y: Some(Mm(self.current_y - final_height)),
-                scale_x: Some(scale),
-                scale_y: Some(scale),
-                ..Default::default()
-            },
-        );
-        
-        self.current_y -= final_height + 10.0; // Add some space after the image
+        for page in block.top.0..=bottom.0 {
+            let top_y = if page == block.top.0 { block.top.1 } else { self.page_top() };
+            let bottom_y = if page == bottom.0 { bottom.1 } else { self.margins.bottom };
+            let color = style.background_color.map(|color| Paint::Color(faded(color, style.text.opacity)));
+            let image = image.clone().map(|image| Paint::Image(image, style.text.opacity));
+            for paint in color.into_iter().chain(image) {
+                self.backgrounds.push(Background {
+                    seq: block.seq,
+                    page,
+                    rect: (block.left, bottom_y, block.right, top_y),
+                    paint,
+                });
+            }
+
+            let layer = &self.pages[page].content;
+            let states = &mut self.alpha_states;
+            let mut stroke = |side: &Border, from: (f32, f32), to: (f32, f32)| {
+                if side.used_width() > 0.0 {
+                    let color = faded(side.color.unwrap_or(style.text.color), style.text.opacity);
+                    stroke_line(states, layer, page, from, to, side.width, color);
+                }
+            };
+            let x = block.left + border.left.width / 2.0;
+            stroke(&border.left, (x, top_y), (x, bottom_y));
+            let x = block.right - border.right.width / 2.0;
+            stroke(&border.right, (x, top_y), (x, bottom_y));
+            if page == block.top.0 {
+                let y = top_y - border.top.width / 2.0;
+                stroke(&border.top, (block.left, y), (block.right, y));
+            }
+            if page == bottom.0 {
+                let y = bottom_y + border.bottom.width / 2.0;
+                stroke(&border.bottom, (block.left, y), (block.right, y));
+            }
+        }
         Ok(())
     }
 
-    fn add_horizontal_rule(&mut self) -> Result<()> {
-        let line = Line {
-            points: vec![
-                (Point::new(Mm(self.margin), Mm(self.current_y)), false),
-                (Point::new(Mm(self.page_width - self.margin), Mm(self.current_y)), false),
-            ],
-            is_closed: false,
+    fn resolve(&self, path: &Path) -> PathBuf {
+        match &self.base_dir {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    // Images are sized at 96 pixels per inch, like CSS pixels, and scaled
+    // down to fit the line width and the page
+    fn add_image(&mut self, path: &str, opacity: f32) -> Result<()> {
+        self.commit_margin()?;
+        let img = self.resources.image(&self.resolve(Path::new(path)))?;
+        let natural_width = img.width() as f32 * 25.4 / 96.0;
+        let natural_height = img.height() as f32 * 25.4 / 96.0;
+
+        let (left, right) = self.line_bounds();
+        let max_width = right - left;
+        let max_height = self.page_top() - self.margins.bottom;
+        let scale = (max_width / natural_width).min(max_height / natural_height).min(1.0);
+        let width = natural_width * scale;
+        let height = natural_height * scale;
+
+        if self.current_y - height < self.margins.bottom && !self.at_page_top() {
+            self.new_page();
+        }
+
+        // Center the image horizontally within the line
+        let x = left + (max_width - width) / 2.0;
+        let layer = self.current_layer.clone();
+        if self.alpha_states.begin(&layer, self.page_number, opacity) {
+            self.images.draw(&layer, self.page_number, &img, (x, self.current_y - height, width, height));
+        }
+        self.alpha_states.end(&layer, opacity);
+        self.current_y -= height;
+        Ok(())
+    }
+
+    // Pages are only all known once layout is done, so backgrounds go on
+    // last, beneath the block backgrounds painted on the same layer
+    fn paint_page_backgrounds(&mut self) {
+        let (width, height) = (self.page_width, self.page_height);
+        let color = self.page_background.or(self.canvas_background);
+        for (page, layers) in self.pages.iter().enumerate() {
+            if let Some(color) = color {
+                fill_rect(&mut self.alpha_states, &layers.background, page, (0.0, 0.0, width, height), color);
+            }
+            if let Some(img) = &self.page_background_image {
+                // Cover the whole page, cropping whichever side overhangs
+                let natural_width = img.width() as f32 * 25.4 / 96.0;
+                let natural_height = img.height() as f32 * 25.4 / 96.0;
+                let scale = (width / natural_width).max(height / natural_height);
+                let (cover_width, cover_height) = (natural_width * scale, natural_height * scale);
+                let x = (width - cover_width) / 2.0;
+                let y = (height - cover_height) / 2.0;
+                self.images.draw(&layers.background, page, img, (x, y, cover_width, cover_height));
+            }
+        }
+    }
+
+    // Watermarks beneath the content still go above block backgrounds
+    fn stamp_watermarks(&mut self) -> Result<()> {
+        let Some(watermark) = self.watermark.take() else {
+            return Ok(());
+        };
+        let color = parse_color(&watermark.color).unwrap_or((0.5, 0.5, 0.5, 1.0));
+        let runs = match &watermark.content {
+            WatermarkContent::Text(text) => self.fonts.split_runs(text, FontStyle::Bold),
+            WatermarkContent::Image(_) => Vec::new(),
         };
-        self.current_layer.set_outline_color(Color::Rgb(Rgb::new(0.0, 0.0, 0.0, None)));
-        self.current_layer.add_line(line);
-        self.current_y -= 10.0;
+        for page in (0..self.pages.len()).filter(|page| watermark.applies_to(page + 1)) {
+            let layers = &self.pages[page];
+            let layer = if watermark.above_content { layers.content.clone() } else { layers.background.clone() };
+            self.current_layer = layer.clone();
+            let alpha = if self.watermark_image.is_some() { watermark.opacity } else { color.3 * watermark.opacity };
+            if self.alpha_states.begin(&layer, page, alpha) {
+                // Rotate about the middle of the page and centre the stamp on it
+                layer.save_graphics_state();
+                let (x, y) = (Mm(self.page_width / 2.0), Mm(self.page_height / 2.0));
+                layer.set_ctm(CurTransMat::TranslateRotate(x.into(), y.into(), watermark.angle));
+                if let Some(img) = &self.watermark_image {
+                    let height = watermark.size * img.height() as f32 / img.width().max(1) as f32;
+                    let rect = (-watermark.size / 2.0, -height / 2.0, watermark.size, height);
+                    self.images.draw(&layer, page, img, rect);
+                } else {
+                    layer.set_fill_color(Color::Rgb(Rgb::new(color.0, color.1, color.2, None)));
+                    let width: f32 = runs.iter().map(|run| run.width_mm(watermark.size)).sum();
+                    let (ascent, descent) = runs.first().map_or((0.0, 0.0), |run| {
+                        (run.ascent_mm(watermark.size), run.descent_mm(watermark.size))
+                    });
+                    let mut x = -width / 2.0;
+                    let baseline = (descent - ascent) / 2.0;
+                    for run in &runs {
+                        self.draw_run(run, watermark.size, x, baseline)?;
+                        x += run.width_mm(watermark.size);
+                    }
+                }
+                layer.restore_graphics_state();
+            }
+            self.alpha_states.end(&layer, alpha);
+        }
         Ok(())
     }
 
-    pub fn save_to_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        let file = File::create(path).map_err(|e| Mark2PdfError::IoError(e))?;
-        let mut writer = BufWriter::new(file);
-        let doc = std::mem::replace(&mut self.doc, PdfDocument::new("New Page", Mm(self.page_width), Mm(self.page_height), "Layer 1").0);
-        doc.save(&mut writer).map_err(|e| Mark2PdfError::PdfError(e.to_string()))?;
+    // Headers and footers are centred in the middle of the top and bottom
+    // margins, a little smaller than the body text
+    fn draw_running_text(&mut self) -> Result<()> {
+        let font_size = self.format_stack[0].font_size * 0.8;
+        let pages = self.pages.len();
+        let lines = [
+            (self.header.take(), self.page_height - self.margins.top / 2.0),
+            (self.footer.take(), self.margins.bottom / 2.0),
+        ];
+        for (template, middle) in lines {
+            let Some(template) = template else { continue };
+            for page in 0..pages {
+                let text = template.replace("{page}", &(page + 1).to_string()).replace("{pages}", &pages.to_string());
+                let runs = self.fonts.split_runs(&text, FontStyle::Regular);
+                let width: f32 = runs.iter().map(|run| run.width_mm(font_size)).sum();
+                let Some(first) = runs.first() else { continue };
+                let baseline = middle - (first.ascent_mm(font_size) - first.descent_mm(font_size)) / 2.0;
+                self.current_layer = self.pages[page].content.clone();
+                self.current_layer.set_fill_color(Color::Rgb(Rgb::new(0.4, 0.4, 0.4, None)));
+                let mut x = (self.page_width - width) / 2.0;
+                for run in &runs {
+                    self.draw_run(run, font_size, x, baseline)?;
+                    x += run.width_mm(font_size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        self.save_to(File::create(path)?)
+    }
+
+    /// Finishes the pages and writes the document to `writer`.
+    pub fn save_to<W: Write>(mut self, writer: W) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        self.paint_page_backgrounds();
+        let mut backgrounds = std::mem::take(&mut self.backgrounds);
+        backgrounds.sort_by_key(|background| background.seq);
+        for Background { page, rect, paint, .. } in backgrounds {
+            let layer = &self.pages[page].background;
+            match paint {
+                Paint::Color(color) => fill_rect(&mut self.alpha_states, layer, page, rect, color),
+                Paint::Image(img, opacity) => {
+                    let (left, bottom, right, top) = rect;
+                    if self.alpha_states.begin(layer, page, opacity) {
+                        self.images.draw(layer, page, &img, (left, bottom, right - left, top - bottom));
+                    }
+                    self.alpha_states.end(layer, opacity);
+                }
+            }
+        }
+        self.stamp_watermarks()?;
+        self.draw_running_text()?;
+        // Headers, footers and watermarks have been split into runs by now too
+        self.fonts.report_missing();
+        if self.alpha_states.used.is_empty() && self.images.used.is_empty() {
+            self.doc.save(&mut writer).map_err(|e| Mark2PdfError::PdfError(e.to_string()))?;
+        } else {
+            let bytes = self.doc.save_to_bytes()?;
+            let mut pdf = lopdf::Document::load_mem(&bytes).map_err(|e| Mark2PdfError::PdfError(e.to_string()))?;
+            self.alpha_states.add_to(&mut pdf)?;
+            self.images.add_to(&mut pdf)?;
+            pdf.save_to(&mut writer)?;
+        }
+        writer.flush()?;
         Ok(())
     }
 }
 
+impl Default for PdfState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub fn html_to_pdf(html: &str, output_path: &Path) -> Result<()> {
+    html_to_pdf_with_config(html, output_path, &Config::default())
+}
+
+pub fn html_to_pdf_with_config(html: &str, output_path: &Path, config: &Config) -> Result<()> {
+    // The file is only created once the layout has succeeded
+    layout_document(html, config, &mut Resources::new())?.save_to_file(output_path)
+}
+
+/// Renders `html` and writes the PDF to `writer`.
+pub fn html_to_pdf_writer<W: Write>(html: &str, writer: W, config: &Config) -> Result<()> {
+    layout_document(html, config, &mut Resources::new())?.save_to(writer)
+}
+
+/// Lays out `html`, loading fonts, images and stylesheets through
+/// `resources`. The result still has to be saved.
+pub fn layout_document(html: &str, config: &Config, resources: &mut Resources) -> Result<PdfState> {
+    config.validate()?;
     let dom = parse_document(RcDom::default(), Default::default())
         .from_utf8()
         .read_from(&mut html.as_bytes())
         .unwrap();
 
-    let mut pdf = PdfState::new();
-    process_node(&dom.document, &mut pdf, true)?;
-    pdf.save_to_file(output_path)?;
+    let (page_width, page_height) = config.page_size();
+    let mut pdf = PdfState::with_page(page_width, page_height, config.margins);
+    // The resources are handed back even when the layout fails
+    pdf.resources = std::mem::take(resources);
+    let result = lay_out(&dom.document, config, &mut pdf);
+    *resources = std::mem::take(&mut pdf.resources);
+    result?;
+    Ok(pdf.with_metadata(config))
+}
+
+fn lay_out(document: &Handle, config: &Config, pdf: &mut PdfState) -> Result<()> {
+    pdf.configure(config)?;
+    let mut styles = StyleEngine::new(config.font_size);
+    styles.set_print_background(config.print_background);
+    collect_stylesheets(document, &mut styles, &mut pdf.resources);
+    pdf.base_dir = base_dir(document);
+    if let Some(family) = &config.font_family {
+        // The configured family replaces the stylesheet's body font
+        styles.add_stylesheet(Stylesheet::parse(&format!("body {{ font-family: {} }}", family)));
+    }
+    let root = ComputedStyle::root(pdf.current_format());
+    process_node(document, pdf, &styles, &root)?;
+    pdf.flush_inline()?;
     Ok(())
 }
 
-fn process_node(handle: &Handle, pdf: &mut PdfState, root: bool) -> Result<()> {
-    let node = handle;
-    match node.data {
-        NodeData::Text { ref contents } => {
-            let text = contents.borrow().to_string();
-            if !text.trim().is_empty() {
-                pdf.write_text(&text)?;
-                pdf.add_vertical_space(5.0)?;
+// Adds the contents of every `<style>` element, in document order
+fn collect_stylesheets(handle: &Handle, styles: &mut StyleEngine, resources: &mut Resources) {
+    if let NodeData::Element { ref name, .. } = handle.data {
+        if name.local.as_ref() == "style" {
+            let mut css = String::new();
+            for child in handle.children.borrow().iter() {
+                if let NodeData::Text { ref contents } = child.data {
+                    css.push_str(&contents.borrow());
+                }
             }
+            styles.add_stylesheet(resources.stylesheet(&css));
+            return;
         }
-        NodeData::Element { ref name, ref attrs, .. } => {
-            let format = pdf.current_format();
-            let mut new_format = format.clone();
+    }
+    for child in handle.children.borrow().iter() {
+        collect_stylesheets(child, styles, resources);
+    }
+}
 
-            match name.local.as_ref() {
-                "h1" => {
-                    new_format.font_size = 24.0;
-                    new_format.is_bold = true;
-                    pdf.add_vertical_space(20.0)?;
-                }
-                "h2" => {
-                    new_format.font_size = 20.0;
-                    new_format.is_bold = true;
-                    pdf.add_vertical_space(15.0)?;
-                }
-                "h3" => {
-                    new_format.font_size = 16.0;
-                    new_format.is_bold = true;
-                    pdf.add_vertical_space(10.0)?;
-                }
-                "h4" => {
-                    new_format.font_size = 14.0;
-                    new_format.is_bold = true;
-                    pdf.add_vertical_space(8.0)?;
-                }
-                "h5" => {
-                    new_format.font_size = 11.0;
-                    new_format.is_bold = true;
-                    pdf.add_vertical_space(6.0)?;
-                }
-                "h6" => {
-                    new_format.font_size = 10.0;
-                    new_format.is_bold = true;
-                    new_format.color = (0.4, 0.4, 0.4);  // Slightly muted color
-                    pdf.add_vertical_space(6.0)?;
-                }
-                "strong" | "b" => {
-                    new_format.is_bold = true;
-                }
-                "em" | "i" => {
-                    new_format.is_italic = true;
-                }
-                "u" => {
-                    new_format.is_underline = true;
-                }
-                "code" => {
-                    new_format.font_size = 11.0;
-                    new_format.background_color = Some((0.973, 0.973, 0.973));  // #f8f8f8
-                }
-                "pre" => {
-                    new_format.font_size = 11.0;
-                    new_format.background_color = Some((0.973, 0.973, 0.973));  // #f8f8f8
-                    pdf.add_vertical_space(10.0)?;
-                }
-                "a" => {
-                    new_format.color = (0.204, 0.596, 0.859);  // #3498db
-                    new_format.is_underline = true;
-                }
-                "blockquote" => {
-                    new_format.color = (0.4, 0.4, 0.4);
-                    new_format.is_italic = true;
-                    pdf.add_vertical_space(10.0)?;
-                    
-                    // Add left border
-                    let line = Line {
-                        points: vec![
-                            (Point::new(Mm(pdf.margin + 2.0), Mm(pdf.current_y + 2.0)), false),
-                            (Point::new(Mm(pdf.margin + 2.0), Mm(pdf.current_y - 20.0)), false),
-                        ],
-                        is_closed: false,
-                    };
-                    pdf.current_layer.set_outline_color(Color::Rgb(Rgb::new(0.863, 0.863, 0.863, None)));  // #dcdcdc
-                    pdf.current_layer.set_outline_thickness(2.0);
-                    pdf.current_layer.add_line(line);
-                    
-                    // Add padding for the text
-                    new_format.alignment = TextAlignment::Left;
-                    pdf.margin += 15.0;
-                }
-                "table" => {
-                    pdf.add_vertical_space(10.0)?;
-                }
-                "tr" => {
-                    // Add cell padding
-                    pdf.add_vertical_space(5.0)?;
-                }
-                "th" => {
-                    new_format.is_bold = true;
-                    new_format.background_color = Some((0.973, 0.973, 0.973));  // #f8f8f8
-                }
-                "td" => {
-                    // Add cell padding
-                    pdf.margin += 5.0;
-                }
-                "span" => {
-                    for attr in attrs.borrow().iter() {
-                        if attr.name.local.as_ref() == "style" {
-                            for style in attr.value.split(';') {
-                                let parts: Vec<&str> = style.split(':').map(|s| s.trim()).collect();
-                                if parts.len() == 2 {
-                                    match parts[0] {
-                                        "color" => {
-                                            if let Some(color) = parse_color(parts[1]) {
-                                                new_format.color = color;
-                                            }
-                                        }
-                                        "background-color" => {
-                                            if let Some(color) = parse_color(parts[1]) {
-                                                new_format.background_color = Some(color);
-                                            }
-                                        }
-                                        "text-decoration" if parts[1] == "underline" => {
-                                            new_format.is_underline = true;
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                "div" => {
-                    for attr in attrs.borrow().iter() {
-                        if attr.name.local.as_ref() == "style" {
-                            for style in attr.value.split(';') {
-                                let parts: Vec<&str> = style.split(':').map(|s| s.trim()).collect();
-                                if parts.len() == 2 {
-                                    match parts[0] {
-                                        "text-align" => {
-                                            new_format.alignment = match parts[1] {
-                                                "center" => TextAlignment::Center,
-                                                "right" => TextAlignment::Right,
-                                                _ => TextAlignment::Left,
-                                            };
-                                        }
-                                        "color" => {
-                                            if let Some(color) = parse_color(parts[1]) {
-                                                new_format.color = color;
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                        } else if attr.name.local.as_ref() == "class" && attr.value.as_ref() == "page-break" {
-                            // Add a new page
-                            let (page_idx, layer_idx) = pdf.doc.add_page(Mm(pdf.page_width), Mm(pdf.page_height), "Layer 1");
-                            let page = pdf.doc.get_page(page_idx);
-                            pdf.current_layer = page.get_layer(layer_idx);
-                            pdf.current_page = page_idx;
-                            pdf.current_y = pdf.page_height - pdf.margin;
-                            return Ok(());
-                        }
-                    }
-                }
-                "hr" => {
-                    pdf.add_horizontal_rule()?;
-                    return Ok(());
-                }
-                "img" => {
-                    for attr in attrs.borrow().iter() {
-                        if attr.name.local.as_ref() == "src" {
-                            pdf.add_image(&attr.value)?;
-                            return Ok(());
-                        }
-                    }
-                }
-                "p" | "br" => {
-                    if !root {
-                        pdf.add_vertical_space(10.0)?;
-                    }
-                }
-                _ => {}
+// The directory named by the first `<base href>`
+fn base_dir(handle: &Handle) -> Option<PathBuf> {
+    if let NodeData::Element { ref name, .. } = handle.data {
+        if name.local.as_ref() == "base" {
+            if let Some(href) = css::attribute(handle, "href") {
+                return Some(PathBuf::from(href.strip_prefix("file://").unwrap_or(&href)));
             }
+        }
+    }
+    handle.children.borrow().iter().find_map(base_dir)
+}
 
-            let old_margin = pdf.margin;
-            pdf.push_format(new_format);
-            for child in node.children.borrow().iter() {
-                process_node(child, pdf, false)?;
-            }
-            pdf.pop_format();
-            pdf.margin = old_margin;
+fn process_node(handle: &Handle, pdf: &mut PdfState, styles: &StyleEngine, parent: &ComputedStyle) -> Result<()> {
+    let NodeData::Element { ref name, .. } = handle.data else {
+        if let NodeData::Text { ref contents } = handle.data {
+            pdf.push_text(&contents.borrow());
+            return Ok(());
+        }
+        return process_children(handle, pdf, styles, parent);
+    };
 
-            match name.local.as_ref() {
-                "p" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "div" | "blockquote" | "table" | "tr" => {
-                    pdf.add_vertical_space(10.0)?;
-                }
-                "pre" => {
-                    pdf.add_vertical_space(10.0)?;
-                }
-                _ => {}
+    let mut style = styles.compute(handle, parent);
+    let tag = name.local.as_ref();
+    if matches!(tag, "html" | "body") && pdf.canvas_background.is_none() {
+        // Like the browser canvas, the page takes the root background,
+        // falling back to the body's
+        pdf.canvas_background = style.background_color.take();
+    }
+    match style.display {
+        Display::None => {}
+        _ if tag == "br" => pdf.push_line_break(),
+        _ if tag == "img" => {
+            if let Some(src) = css::attribute(handle, "src") {
+                let block = pdf.open_block(&style)?;
+                pdf.add_image(&src, style.text.opacity)?;
+                pdf.close_block(block)?;
             }
         }
-        _ => {
-            for child in node.children.borrow().iter() {
-                process_node(child, pdf, false)?;
+        Display::Inline => {
+            if style.border.has_visible_side() {
+                pdf.inline_box_count += 1;
+                style.text.inline_box = Some(pdf.inline_box_count);
             }
+            pdf.push_format(style.text.clone());
+            process_children(handle, pdf, styles, &style)?;
+            pdf.pop_format();
+        }
+        Display::TableRow => layout_table_row(handle, pdf, styles, &style)?,
+        Display::Block | Display::ListItem | Display::TableCell => {
+            let block = pdf.open_block(&style)?;
+            let list = matches!(tag, "ul" | "ol" | "menu");
+            if list {
+                let start = css::attribute(handle, "start").and_then(|start| start.trim().parse().ok());
+                pdf.list_stack.push(start.unwrap_or(1));
+            }
+            if style.display == Display::ListItem {
+                let number = match pdf.list_stack.last_mut() {
+                    Some(next) => {
+                        *next += 1;
+                        *next - 1
+                    }
+                    None => 1,
+                };
+                pdf.pending_marker = style.list_style.marker(number);
+            }
+            process_children(handle, pdf, styles, &style)?;
+            if list {
+                pdf.list_stack.pop();
+            }
+            pdf.close_block(block)?;
         }
     }
     Ok(())
 }
 
-fn parse_color(color_str: &str) -> Option<(f32, f32, f32)> {
-    if color_str.starts_with('#') {
-        let hex = &color_str[1..];
-        if hex.len() == 6 {
-            let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
-            let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
-            let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
-            Some((r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0))
-        } else if hex.len() == 3 {
-            // Support #RGB format
-            let r = u8::from_str_radix(&hex[0..1].repeat(2), 16).ok()?;
-            let g = u8::from_str_radix(&hex[1..2].repeat(2), 16).ok()?;
-            let b = u8::from_str_radix(&hex[2..3].repeat(2), 16).ok()?;
-            Some((r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0))
-        } else {
-            None
+fn process_children(handle: &Handle, pdf: &mut PdfState, styles: &StyleEngine, style: &ComputedStyle) -> Result<()> {
+    for child in handle.children.borrow().iter() {
+        process_node(child, pdf, styles, style)?;
+    }
+    Ok(())
+}
+
+// Lays the cells of a row out side by side in equal columns, each starting
+// at the top of the row. Cell boxes are stretched to the tallest cell.
+fn layout_table_row(handle: &Handle, pdf: &mut PdfState, styles: &StyleEngine, style: &ComputedStyle) -> Result<()> {
+    let row = pdf.open_block(style)?;
+    let cells: Vec<(Handle, ComputedStyle)> = handle
+        .children
+        .borrow()
+        .iter()
+        .filter(|child| matches!(child.data, NodeData::Element { .. }))
+        .map(|child| (child.clone(), styles.compute(child, style)))
+        .filter(|(_, cell_style)| cell_style.display != Display::None)
+        .collect();
+    pdf.commit_margin()?;
+
+    let start = pdf.position();
+    let indents = (pdf.indent_left, pdf.indent_right);
+    let (left, right) = pdf.line_bounds();
+    let count = cells.len();
+    let column = (right - left) / count.max(1) as f32;
+    let mut end = start;
+    let mut blocks = Vec::with_capacity(count);
+    for (index, (cell, cell_style)) in cells.iter().enumerate() {
+        let index = if style.text.direction == TextDirection::Rtl { count - 1 - index } else { index };
+        pdf.goto(start);
+        pdf.pending_margin = 0.0;
+        pdf.indent_left = indents.0 + column * index as f32;
+        pdf.indent_right = indents.1 + column * (count - 1 - index) as f32;
+        let block = pdf.open_block(cell_style)?;
+        process_children(cell, pdf, styles, cell_style)?;
+        let bottom = pdf.finish_block(&block)?;
+        pdf.end_block(&block);
+        // The lowest point is on the last page any cell reached
+        if bottom.0 > end.0 || (bottom.0 == end.0 && bottom.1 < end.1) {
+            end = bottom;
         }
-    } else {
-        match color_str {
-            "black" => Some((0.0, 0.0, 0.0)),
-            "red" => Some((1.0, 0.0, 0.0)),
-            "green" => Some((0.0, 1.0, 0.0)),
-            "blue" => Some((0.0, 0.0, 1.0)),
-            "white" => Some((1.0, 1.0, 1.0)),
-            "gray" | "grey" => Some((0.5, 0.5, 0.5)),
-            "gainsboro" => Some((0.863, 0.863, 0.863)),  // #dcdcdc
-            "whitesmoke" => Some((0.961, 0.961, 0.961)), // #f5f5f5
-            _ => None,
-        }
-    }
-}
\ No newline at end of file
+        blocks.push(block);
+    }
+    for block in &blocks {
+        pdf.paint_block(block, end)?;
+    }
+    pdf.goto(end);
+    pdf.pending_margin = 0.0;
+    (pdf.indent_left, pdf.indent_right) = indents;
+    pdf.close_block(row)
+}
+
+fn fill_rect(
+    states: &mut AlphaStates,
+    layer: &PdfLayerReference,
+    page: usize,
+    (x1, y1, x2, y2): (f32, f32, f32, f32),
+    color: Rgba,
+) {
+    if states.begin(layer, page, color.3) {
+        layer.set_fill_color(Color::Rgb(Rgb::new(color.0, color.1, color.2, None)));
+        layer.add_rect(Rect::new(Mm(x1), Mm(y1), Mm(x2), Mm(y2)));
+    }
+    states.end(layer, color.3);
+}
+
+// `width` is in mm
+fn stroke_line(
+    states: &mut AlphaStates,
+    layer: &PdfLayerReference,
+    page: usize,
+    from: (f32, f32),
+    to: (f32, f32),
+    width: f32,
+    color: Rgba,
+) {
+    if states.begin(layer, page, color.3) {
+        layer.set_outline_color(Color::Rgb(Rgb::new(color.0, color.1, color.2, None)));
+        layer.set_outline_thickness(width * 72.0 / 25.4);
+        layer.add_line(Line {
+            points: vec![
+                (Point::new(Mm(from.0), Mm(from.1)), false),
+                (Point::new(Mm(to.0), Mm(to.1)), false),
+            ],
+            is_closed: false,
+        });
+    }
+    states.end(layer, color.3);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lays out `html` and reads the PDF back
+    fn render(html: &str, config: &Config) -> lopdf::Document {
+        let mut bytes = Vec::new();
+        html_to_pdf_writer(html, &mut bytes, config).unwrap();
+        lopdf::Document::load_mem(&bytes).unwrap()
+    }
+
+    // The content stream of every page, in page order
+    fn contents(pdf: &lopdf::Document) -> Vec<String> {
+        pdf.get_pages()
+            .values()
+            .map(|id| String::from_utf8(pdf.get_page_content(*id).unwrap()).unwrap())
+            .collect()
+    }
+
+    fn resources(pdf: &lopdf::Document, page: u32) -> &lopdf::Dictionary {
+        let page = pdf.get_dictionary(pdf.get_pages()[&page]).unwrap();
+        match page.get(b"Resources").unwrap() {
+            lopdf::Object::Reference(id) => pdf.get_dictionary(*id).unwrap(),
+            resources => resources.as_dict().unwrap(),
+        }
+    }
+
+    // The angle of the first transformation matrix in `content`, in degrees
+    fn rotation(content: &str) -> f32 {
+        let matrix = content.lines().find(|line| line.ends_with(" cm")).unwrap();
+        let numbers: Vec<f32> = matrix.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+        numbers[1].atan2(numbers[0]).to_degrees()
+    }
+
+    #[test]
+    fn test_transparency_adds_graphics_states() {
+        let html = r#"<p style="color: rgba(255, 0, 0, 0.5)">half</p><div style="opacity: 0.25; background: navy">quarter</div>"#;
+        let pdf = render(html, &Config::new());
+        let states = resources(&pdf, 1).get(b"ExtGState").unwrap().as_dict().unwrap();
+        assert!(states.has(b"Alpha128"));
+        assert!(states.has(b"Alpha64"));
+    }
+
+    #[test]
+    fn test_graphics_states_join_inline_page_resources() {
+        let mut pdf = lopdf::Document::with_version("1.5");
+        let pages_id = pdf.new_object_id();
+        let existing = lopdf::Dictionary::from_iter(vec![("GS0", lopdf::Object::Dictionary(lopdf::Dictionary::new()))]);
+        let resources = lopdf::Dictionary::from_iter(vec![("ExtGState", lopdf::Object::Dictionary(existing))]);
+        let page_id = pdf.add_object(lopdf::Dictionary::from_iter(vec![
+            ("Type", lopdf::Object::Name(b"Page".to_vec())),
+            ("Parent", lopdf::Object::Reference(pages_id)),
+            ("Resources", lopdf::Object::Dictionary(resources)),
+        ]));
+        let pages = lopdf::Dictionary::from_iter(vec![
+            ("Type", lopdf::Object::Name(b"Pages".to_vec())),
+            ("Kids", lopdf::Object::Array(vec![lopdf::Object::Reference(page_id)])),
+            ("Count", lopdf::Object::Integer(1)),
+        ]);
+        pdf.objects.insert(pages_id, lopdf::Object::Dictionary(pages));
+        let catalog = pdf.add_object(lopdf::Dictionary::from_iter(vec![
+            ("Type", lopdf::Object::Name(b"Catalog".to_vec())),
+            ("Pages", lopdf::Object::Reference(pages_id)),
+        ]));
+        pdf.trailer.set("Root", lopdf::Object::Reference(catalog));
+
+        let states = AlphaStates { used: BTreeSet::from([(0, 128)]) };
+        states.add_to(&mut pdf).unwrap();
+        let page = pdf.get_dictionary(page_id).unwrap();
+        let states = page.get(b"Resources").unwrap().as_dict().unwrap().get(b"ExtGState").unwrap().as_dict().unwrap();
+        assert!(states.has(b"GS0") && states.has(b"Alpha128"));
+    }
+
+    #[test]
+    fn test_adjacent_keycaps_get_a_box_each() {
+        // Counts the #f4f4f4 keycap fills
+        let keycaps = |html: &str| contents(&render(html, &Config::new()))[0].matches("0.95686275 0.95686275 0.95686275 rg").count();
+        assert_eq!(keycaps("<p><kbd>Ctrl</kbd><kbd>C</kbd></p>"), 2);
+        assert_eq!(keycaps("<p><kbd>Shift <b>F</b></kbd></p>"), 1);
+    }
+
+    #[test]
+    fn test_page_background_on_every_page() {
+        let html = r#"<p style="break-after: page">one</p><p style="background: lime">two</p>"#;
+        let config = Config::new().with_page_background("red").with_print_background(false);
+        let contents = contents(&render(html, &config));
+        assert_eq!(contents.len(), 2);
+        for content in contents {
+            assert!(content.contains("1 0 0 rg"));
+            assert!(!content.contains("0 1 0 rg"));
+        }
+    }
+
+    #[test]
+    fn test_transparent_image_keeps_soft_mask() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("logo.png");
+        ::image::RgbaImage::from_pixel(4, 2, ::image::Rgba([200, 0, 0, 100])).save(&image).unwrap();
+        let pdf = render(&format!(r#"<p><img src="{}"></p>"#, image.display()), &Config::new());
+        let xobjects = resources(&pdf, 1).get(b"XObject").unwrap().as_dict().unwrap();
+        let image = pdf.get_object(xobjects.get(b"Image0").unwrap().as_reference().unwrap()).unwrap().as_stream().unwrap();
+        let mask = pdf.get_object(image.dict.get(b"SMask").unwrap().as_reference().unwrap()).unwrap().as_stream().unwrap();
+        assert_eq!(mask.dict.get(b"Height").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(mask.decompressed_content().unwrap_or(mask.content.clone()), vec![100; 8]);
+    }
+
+    #[test]
+    fn test_text_watermark_on_selected_pages() {
+        let html = r#"<p style="break-after: page">one</p><p>two</p>"#;
+        let watermark = Watermark::text("DRAFT").with_pages("2".parse().unwrap());
+        let contents = contents(&render(html, &Config::new().with_watermark(watermark)));
+        // "DRAFT" and "two" in hex, as printpdf writes builtin font text
+        assert!(!contents[0].contains("<4452414654>"));
+        let stamp = contents[1].find("<4452414654>").unwrap();
+        assert!(stamp < contents[1].find("<74776F>").unwrap());
+        assert!(contents[1].contains("/Alpha51 gs"));
+        assert!((rotation(&contents[1]) - 45.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_image_watermark_above_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("logo.png");
+        ::image::RgbImage::from_pixel(4, 2, ::image::Rgb([200, 0, 0])).save(&image).unwrap();
+        let html = r#"<p style="break-after: page">one</p><p>two</p>"#;
+        let watermark = Watermark::image(&image).with_angle(-30.0).with_above_content(true).with_pages("1".parse().unwrap());
+        let contents = contents(&render(html, &Config::new().with_watermark(watermark)));
+        // "one" in hex
+        assert!(contents[0].find("/Image0 Do").unwrap() > contents[0].find("<6F6E65>").unwrap());
+        assert!((rotation(&contents[0]) + 30.0).abs() < 0.01);
+        assert!(!contents[1].contains(" Do"));
+    }
+
+    #[test]
+    fn test_background_image_fills_the_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("paper.png");
+        ::image::RgbImage::from_pixel(2, 2, ::image::Rgb([250, 240, 200])).save(&image).unwrap();
+        let html = format!(r#"<div style="background: url('{}') white; padding: 5mm">boxed</div>"#, image.display());
+        let xobjects = |config: &Config| {
+            let pdf = render(&html, config);
+            resources(&pdf, 1).get(b"XObject").map_or(0, |xobjects| xobjects.as_dict().unwrap().len())
+        };
+        assert_eq!(xobjects(&Config::new()), 1);
+        assert_eq!(xobjects(&Config::new().with_print_background(false)), 0);
+    }
+}
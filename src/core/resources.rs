@@ -0,0 +1,159 @@
+use crate::core::css::{self, Stylesheet};
+use crate::core::font_db::FontDatabase;
+use crate::core::fonts::FontFace;
+use crate::error::{Mark2PdfError, Result};
+use image::DynamicImage;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::hash::Hash;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Fonts, images and stylesheets kept between conversions, so converting
+/// the same document again only reloads the files that changed. It also
+/// records every file a conversion reads.
+#[derive(Default)]
+pub struct Resources {
+    // Installed fonts, scanned for one list of extra font directories
+    font_db: Option<(Vec<PathBuf>, FontDatabase)>,
+    fonts: Cache<(PathBuf, Option<SystemTime>), FontFace>,
+    images: Cache<(PathBuf, Option<SystemTime>), Arc<DynamicImage>>,
+    stylesheet_files: Cache<(PathBuf, Option<SystemTime>), String>,
+    // Parsed stylesheets by their text
+    stylesheets: Cache<String, Arc<Stylesheet>>,
+    dependencies: BTreeSet<PathBuf>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a conversion. Anything the previous conversion did not use is
+    /// dropped once this one finishes with it.
+    pub fn begin(&mut self) {
+        self.fonts.begin();
+        self.images.begin();
+        self.stylesheet_files.begin();
+        self.stylesheets.begin();
+        self.dependencies.clear();
+    }
+
+    /// The files read since [`Resources::begin`], including ones that could
+    /// not be read.
+    pub fn dependencies(&self) -> impl Iterator<Item = &Path> {
+        self.dependencies.iter().map(PathBuf::as_path)
+    }
+
+    pub fn add_dependency<P: Into<PathBuf>>(&mut self, path: P) {
+        self.dependencies.insert(path.into());
+    }
+
+    pub fn font_db(&mut self, extra_dirs: &[PathBuf]) -> &mut FontDatabase {
+        if self.font_db.as_ref().is_none_or(|(dirs, _)| dirs != extra_dirs) {
+            self.font_db = Some((extra_dirs.to_vec(), FontDatabase::system(extra_dirs)));
+        }
+        &mut self.font_db.as_mut().expect("font database was just scanned").1
+    }
+
+    pub fn font(&mut self, path: &Path) -> Result<FontFace> {
+        let key = self.file_key(path);
+        self.fonts.get_or_load(key, || FontFace::from_file(path))
+    }
+
+    pub fn image(&mut self, path: &Path) -> Result<Arc<DynamicImage>> {
+        let key = self.file_key(path);
+        self.images.get_or_load(key, || {
+            let img = ::image::open(path).map_err(|e| Mark2PdfError::ImageError(format!("{}: {}", path.display(), e)))?;
+            Ok(Arc::new(img))
+        })
+    }
+
+    /// Reads a stylesheet file with its `url()`s made absolute.
+    pub fn stylesheet_file(&mut self, path: &Path) -> Result<String> {
+        let key = self.file_key(path);
+        self.stylesheet_files.get_or_load(key, || {
+            let css = fs::read_to_string(path)
+                .map_err(|e| Mark2PdfError::ConfigError(format!("cannot read stylesheet '{}': {}", path.display(), e)))?;
+            let dir = path.parent().unwrap_or(Path::new(""));
+            let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+            Ok(css::rebase_urls(&css, &dir))
+        })
+    }
+
+    pub fn stylesheet(&mut self, css: &str) -> Arc<Stylesheet> {
+        let parsed: Result<_> = self.stylesheets.get_or_load(css.to_string(), || Ok(Arc::new(Stylesheet::parse(css))));
+        parsed.expect("parsing CSS cannot fail")
+    }
+
+    // Files are reloaded when their modification time changes
+    fn file_key(&mut self, path: &Path) -> (PathBuf, Option<SystemTime>) {
+        self.add_dependency(path);
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        (path.to_path_buf(), modified)
+    }
+}
+
+// Entries used by the current conversion, and those of the previous one
+// that are still waiting to be used again
+struct Cache<K, T> {
+    current: HashMap<K, T>,
+    previous: HashMap<K, T>,
+}
+
+impl<K, T> Default for Cache<K, T> {
+    fn default() -> Self {
+        Self {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: Clone> Cache<K, T> {
+    fn begin(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    fn get_or_load(&mut self, key: K, load: impl FnOnce() -> Result<T>) -> Result<T> {
+        if let Some(value) = self.current.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = match self.previous.remove(&key) {
+            Some(value) => value,
+            None => load()?,
+        };
+        self.current.insert(key, value.clone());
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_files_are_reloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("style.css");
+        fs::write(&path, "p { color: red }").unwrap();
+        let mut resources = Resources::new();
+        resources.begin();
+        let first = resources.stylesheet_file(&path).unwrap();
+        let parsed = resources.stylesheet(&first);
+        assert!(Arc::ptr_eq(&parsed, &resources.stylesheet(&first)));
+        assert!(resources.image(&dir.path().join("missing.png")).is_err());
+        assert_eq!(resources.dependencies().count(), 2);
+
+        resources.begin();
+        fs::write(&path, "p { color: blue; margin: 0 }").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5)).unwrap();
+        let second = resources.stylesheet_file(&path).unwrap();
+        assert!(second.contains("blue"));
+        assert_eq!(resources.dependencies().collect::<Vec<_>>(), vec![path.as_path()]);
+        assert!(!Arc::ptr_eq(&parsed, &resources.stylesheet(&second)));
+        assert!(Arc::ptr_eq(&parsed, &resources.stylesheet(&first)));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::core::fonts::FontFace;
+use rustybuzz::{Direction, UnicodeBuffer};
+
+/// A positioned glyph produced by the shaper. Advances and offsets are in
+/// thousandths of an em so they scale with the font size like the builtin
+/// font metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShapedGlyph {
+    pub glyph_id: u16,
+    // Byte offset of the first character of this glyph's cluster
+    pub cluster: u32,
+    pub x_advance: f32,
+    pub x_offset: f32,
+    pub y_offset: f32,
+}
+
+/// Runs the OpenType shaper over `text`, applying the font's default
+/// features (kerning, standard ligatures, contextual forms, mark placement).
+/// Glyphs are returned in visual order. When `rtl` is `None` the direction
+/// is guessed from the script.
+pub fn shape(face: &FontFace, text: &str, rtl: Option<bool>) -> Vec<ShapedGlyph> {
+    let hb_face = face.shaping_face();
+    let scale = 1000.0 / hb_face.units_per_em() as f32;
+
+    let mut buffer = UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.guess_segment_properties();
+    match rtl {
+        Some(true) => buffer.set_direction(Direction::RightToLeft),
+        Some(false) => buffer.set_direction(Direction::LeftToRight),
+        None => {}
+    }
+
+    let output = rustybuzz::shape(hb_face, &[], buffer);
+    output
+        .glyph_infos()
+        .iter()
+        .zip(output.glyph_positions())
+        .map(|(info, pos)| ShapedGlyph {
+            glyph_id: info.glyph_id as u16,
+            cluster: info.cluster,
+            x_advance: pos.x_advance as f32 * scale,
+            x_offset: pos.x_offset as f32 * scale,
+            y_offset: pos.y_offset as f32 * scale,
+        })
+        .collect()
+}
+
+/// Converts shaped glyphs into `TJ` operands for a font whose widths array
+/// holds the unshaped advances, grouped by vertical offset. Each group is
+/// `(rise, [(adjustment, glyph_id)])` where `rise` is in thousandths of an em.
+pub fn to_tj_groups(face: &FontFace, glyphs: &[ShapedGlyph]) -> Vec<(f32, Vec<(i64, u16)>)> {
+    let ttf = face.face();
+    let scale = 1000.0 / ttf.units_per_em() as f32;
+    // printpdf truncates the scaled advance when it writes the /W array
+    let default_width = |glyph_id: u16| {
+        ttf.glyph_hor_advance(ttf_parser::GlyphId(glyph_id))
+            .map(|advance| (advance as f32 * scale).trunc())
+            .unwrap_or(0.0)
+    };
+
+    let mut groups: Vec<(f32, Vec<(i64, u16)>)> = Vec::new();
+    // Where the PDF text cursor is versus where the shaper wants the pen
+    let mut pdf_x = 0.0f32;
+    let mut pen_x = 0.0f32;
+    for glyph in glyphs {
+        let target = pen_x + glyph.x_offset;
+        let adjustment = (pdf_x - target).round();
+        pdf_x = pdf_x - adjustment + default_width(glyph.glyph_id);
+        pen_x += glyph.x_advance;
+
+        match groups.last_mut() {
+            Some((rise, codepoints)) if *rise == glyph.y_offset => {
+                codepoints.push((adjustment as i64, glyph.glyph_id))
+            }
+            _ => groups.push((glyph.y_offset, vec![(adjustment as i64, glyph.glyph_id)])),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fonts::test_support::dejavu_sans;
+
+    #[test]
+    fn test_shaping_applies_ligatures_and_arabic_joining() {
+        let face = dejavu_sans();
+
+        // DejaVu Sans has an "fi" ligature
+        let latin = shape(&face, "fi", None);
+        assert_eq!(latin.len(), 1);
+
+        // Isolated and joined forms of beh differ
+        let isolated = shape(&face, "ب", None);
+        let joined = shape(&face, "بب", None);
+        assert_eq!(joined.len(), 2);
+        assert!(joined.iter().all(|g| g.glyph_id != isolated[0].glyph_id));
+    }
+
+    #[test]
+    fn test_tj_groups_preserve_shaped_advances() {
+        let face = dejavu_sans();
+        let glyphs = shape(&face, "AV", None);
+        let groups = to_tj_groups(&face, &glyphs);
+        assert_eq!(groups.len(), 1);
+        // Kerning pulls V towards A, which shows up as a positive TJ adjustment
+        assert!(groups[0].1[1].0 > 0);
+    }
+}
@@ -0,0 +1,718 @@
+use crate::config::parse_length;
+use crate::core::color::{parse_color, Rgba};
+use crate::core::css::{self, Declaration, Stylesheet};
+use crate::core::fonts::pt_to_mm;
+use crate::core::pdf::{TextAlignment, TextDirection, TextFormat, LINE_HEIGHT};
+use markup5ever_rcdom::Handle;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+
+// Browser defaults that author stylesheets are layered on top of
+const USER_AGENT_CSS: &str = r#"
+html, body, div, p, h1, h2, h3, h4, h5, h6, blockquote, pre, ul, ol, dl, dt, dd, hr, img,
+figure, figcaption, section, article, header, footer, nav, aside, main, details, summary,
+address, table, thead, tbody, tfoot, caption, form, fieldset { display: block; }
+head, style, script, title, meta, link, template { display: none; }
+li { display: list-item; }
+tr { display: table-row; }
+td, th { display: table-cell; padding: 1px; }
+h1 { font-size: 2em; margin: 0.67em 0; }
+h2 { font-size: 1.5em; margin: 0.83em 0; }
+h3 { font-size: 1.17em; margin: 1em 0; }
+h4 { margin: 1.33em 0; }
+h5 { font-size: 0.83em; margin: 1.67em 0; }
+h6 { font-size: 0.67em; margin: 2.33em 0; }
+h1, h2, h3, h4, h5, h6, b, strong, th { font-weight: bold; }
+i, em, cite, var, dfn { font-style: italic; }
+u, ins, a { text-decoration: underline; }
+s, strike, del { text-decoration: line-through; }
+sup { vertical-align: super; font-size: smaller; }
+sub { vertical-align: sub; font-size: smaller; }
+small { font-size: smaller; }
+mark { background-color: yellow; color: black; }
+kbd { font-size: 0.9em; border: 1px solid #b4b4b4; background-color: #f4f4f4; }
+a { color: #0000ee; }
+p, ul, ol, dl, pre { margin: 1em 0; }
+blockquote { margin: 1em 0; margin-inline-start: 40px; margin-inline-end: 40px; }
+dd { margin-inline-start: 40px; }
+ul, ol { padding-inline-start: 40px; }
+ul { list-style-type: disc; }
+ol { list-style-type: decimal; }
+ul ul { list-style-type: circle; }
+ul ul ul { list-style-type: square; }
+pre, code, kbd, samp, tt { font-family: monospace; }
+pre { white-space: pre; }
+hr { border-top: 1px solid #808080; margin: 0.5em 0; }
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Display {
+    None,
+    Inline,
+    Block,
+    ListItem,
+    TableRow,
+    TableCell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListStyle {
+    None,
+    Disc,
+    Circle,
+    Square,
+    Decimal,
+    LowerAlpha,
+    UpperAlpha,
+    LowerRoman,
+    UpperRoman,
+}
+
+impl ListStyle {
+    fn parse(value: &str) -> Option<Self> {
+        Some(match value {
+            "none" => ListStyle::None,
+            "disc" => ListStyle::Disc,
+            "circle" => ListStyle::Circle,
+            "square" => ListStyle::Square,
+            "decimal" => ListStyle::Decimal,
+            "lower-alpha" | "lower-latin" => ListStyle::LowerAlpha,
+            "upper-alpha" | "upper-latin" => ListStyle::UpperAlpha,
+            "lower-roman" => ListStyle::LowerRoman,
+            "upper-roman" => ListStyle::UpperRoman,
+            _ => return None,
+        })
+    }
+
+    /// The marker drawn before the `number`th item, 1-based.
+    pub fn marker(&self, number: usize) -> Option<String> {
+        let alpha = |number: usize| {
+            let mut letters = Vec::new();
+            let mut n = number;
+            while n > 0 {
+                n -= 1;
+                letters.push((b'a' + (n % 26) as u8) as char);
+                n /= 26;
+            }
+            letters.iter().rev().collect::<String>()
+        };
+        match self {
+            ListStyle::None => None,
+            ListStyle::Disc => Some("•".to_string()),
+            ListStyle::Circle => Some("◦".to_string()),
+            ListStyle::Square => Some("▪".to_string()),
+            ListStyle::Decimal => Some(format!("{}.", number)),
+            ListStyle::LowerAlpha => Some(format!("{}.", alpha(number))),
+            ListStyle::UpperAlpha => Some(format!("{}.", alpha(number).to_uppercase())),
+            ListStyle::LowerRoman => Some(format!("{}.", roman(number))),
+            ListStyle::UpperRoman => Some(format!("{}.", roman(number).to_uppercase())),
+        }
+    }
+}
+
+fn roman(mut number: usize) -> String {
+    const NUMERALS: [(usize, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut output = String::new();
+    for (value, numeral) in NUMERALS {
+        while number >= value {
+            output.push_str(numeral);
+            number -= value;
+        }
+    }
+    output
+}
+
+/// A value for each side of a box.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sides<T> {
+    pub top: T,
+    pub right: T,
+    pub bottom: T,
+    pub left: T,
+}
+
+impl<T: Copy> Sides<T> {
+    // Expands the one to four values of CSS box shorthands
+    fn from_shorthand(values: &[T]) -> Option<Self> {
+        let (top, right, bottom, left) = match *values {
+            [all] => (all, all, all, all),
+            [vertical, horizontal] => (vertical, horizontal, vertical, horizontal),
+            [top, horizontal, bottom] => (top, horizontal, bottom, horizontal),
+            [top, right, bottom, left] => (top, right, bottom, left),
+            _ => return None,
+        };
+        Some(Self { top, right, bottom, left })
+    }
+
+    fn side_mut(&mut self, side: &str, direction: TextDirection) -> Option<&mut T> {
+        let rtl = direction == TextDirection::Rtl;
+        Some(match side {
+            "top" => &mut self.top,
+            "right" => &mut self.right,
+            "bottom" => &mut self.bottom,
+            "left" => &mut self.left,
+            "inline-start" if rtl => &mut self.right,
+            "inline-start" => &mut self.left,
+            "inline-end" if rtl => &mut self.left,
+            "inline-end" => &mut self.right,
+            _ => return None,
+        })
+    }
+
+    fn all_mut(&mut self) -> [&mut T; 4] {
+        [&mut self.top, &mut self.right, &mut self.bottom, &mut self.left]
+    }
+}
+
+impl Sides<Border> {
+    pub fn has_visible_side(&self) -> bool {
+        [self.top, self.right, self.bottom, self.left].iter().any(|side| side.used_width() > 0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Border {
+    // In mm
+    pub width: f32,
+    pub visible: bool,
+    // None draws in the text colour
+    pub color: Option<Rgba>,
+}
+
+impl Border {
+    pub fn used_width(&self) -> f32 {
+        if self.visible {
+            self.width
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Default for Border {
+    fn default() -> Self {
+        Self {
+            width: px(3.0),
+            visible: false,
+            color: None,
+        }
+    }
+}
+
+/// The cascaded and inherited style of one element. Inherited text
+/// properties live in `text`; lengths are in mm.
+#[derive(Debug, Clone)]
+pub struct ComputedStyle {
+    pub display: Display,
+    pub text: TextFormat,
+    pub margin: Sides<f32>,
+    pub padding: Sides<f32>,
+    pub border: Sides<Border>,
+    pub background_color: Option<Rgba>,
+    // Stretched over block boxes; inline elements ignore it
+    pub background_image: Option<PathBuf>,
+    pub list_style: ListStyle,
+    pub break_before: bool,
+    pub break_after: bool,
+}
+
+impl ComputedStyle {
+    /// The style of the document itself, which the root element inherits from.
+    pub fn root(text: TextFormat) -> Self {
+        Self {
+            display: Display::Block,
+            text,
+            margin: Sides::default(),
+            padding: Sides::default(),
+            border: Sides::default(),
+            background_color: None,
+            background_image: None,
+            list_style: ListStyle::Disc,
+            break_before: false,
+            break_after: false,
+        }
+    }
+
+    pub fn has_background(&self) -> bool {
+        self.background_color.is_some() || self.background_image.is_some()
+    }
+
+    fn inherit(parent: &ComputedStyle) -> Self {
+        let mut style = Self::root(parent.text.clone());
+        style.display = Display::Inline;
+        style.list_style = parent.list_style;
+        style
+    }
+
+    fn apply(&mut self, declaration: &Declaration, parent: &ComputedStyle, root_font_size: f32) {
+        let value = declaration.value.trim();
+        let keyword = value.to_ascii_lowercase();
+        let font_size = self.text.font_size;
+        let length = |value: &str| length(value, font_size, root_font_size);
+        let direction = self.text.direction;
+
+        match declaration.name.as_str() {
+            "display" => {
+                if let Some(display) = parse_display(&keyword) {
+                    self.display = display;
+                }
+            }
+            "color" => {
+                if let Some(color) = parse_color(value) {
+                    self.text.color = color;
+                }
+            }
+            "background-image" => self.background_image = css::url_path(value),
+            "background-color" | "background" => {
+                if declaration.name == "background" {
+                    self.background_image = css::url_path(value);
+                }
+                if keyword == "none" || keyword == "transparent" {
+                    self.background_color = None;
+                } else if let Some(color) = css::split_components(value).into_iter().find_map(parse_color) {
+                    self.background_color = Some(color).filter(|color| color.3 > 0.0);
+                }
+            }
+            "opacity" => {
+                let opacity = match keyword.strip_suffix('%') {
+                    Some(percentage) => percentage.parse::<f32>().ok().map(|p| p / 100.0),
+                    None => keyword.parse::<f32>().ok(),
+                };
+                if let Some(opacity) = opacity {
+                    self.text.opacity = parent.text.opacity * opacity.clamp(0.0, 1.0);
+                }
+            }
+            "font-size" => {
+                if let Some(size) = parse_font_size(&keyword, parent.text.font_size, root_font_size) {
+                    self.text.font_size = size;
+                }
+            }
+            "font-weight" => {
+                self.text.is_bold = match keyword.as_str() {
+                    "bold" | "bolder" => true,
+                    "normal" | "lighter" => false,
+                    weight => weight.parse::<u32>().map_or(self.text.is_bold, |weight| weight >= 600),
+                };
+            }
+            "font-style" => self.text.is_italic = keyword == "italic" || keyword.starts_with("oblique"),
+            "font-family" => self.text.font_family = Some(value.to_string()),
+            // Decorations of ancestors still apply to their descendants
+            "text-decoration" | "text-decoration-line" => {
+                let has = |line: &str| keyword.split_whitespace().any(|word| word == line);
+                self.text.is_underline = parent.text.is_underline || has("underline");
+                self.text.is_strikethrough = parent.text.is_strikethrough || has("line-through");
+            }
+            "vertical-align" => {
+                let parent_size = parent.text.font_size;
+                let shift = match keyword.as_str() {
+                    "super" => Some(parent_size * 0.33),
+                    "sub" => Some(-parent_size * 0.2),
+                    "baseline" => Some(0.0),
+                    _ => length(&keyword).map(|mm| mm * 72.0 / 25.4),
+                };
+                if let Some(shift) = shift {
+                    self.text.baseline_shift = parent.text.baseline_shift + shift;
+                }
+            }
+            "text-align" => {
+                self.text.alignment = match keyword.as_str() {
+                    "left" => TextAlignment::Left,
+                    "right" => TextAlignment::Right,
+                    "center" => TextAlignment::Center,
+                    "end" if direction == TextDirection::Rtl => TextAlignment::Left,
+                    "end" => TextAlignment::Right,
+                    _ => TextAlignment::Start,
+                };
+            }
+            "direction" => {
+                if let Some(direction) = parse_direction(&keyword) {
+                    self.text.direction = direction;
+                }
+            }
+            "white-space" => {
+                self.text.preserve_whitespace = matches!(keyword.as_str(), "pre" | "pre-wrap" | "break-spaces");
+            }
+            "line-height" => {
+                let factor = if keyword == "normal" {
+                    Some(LINE_HEIGHT)
+                } else if let Ok(number) = keyword.parse::<f32>() {
+                    Some(number)
+                } else if let Some(percent) = keyword.strip_suffix('%') {
+                    percent.parse::<f32>().ok().map(|percent| percent / 100.0)
+                } else {
+                    length(&keyword).map(|mm| mm / pt_to_mm(font_size))
+                };
+                if let Some(factor) = factor {
+                    self.text.line_height = factor;
+                }
+            }
+            "margin" | "padding" => {
+                let values: Option<Vec<f32>> = keyword.split_whitespace().map(length).collect();
+                if let Some(sides) = values.and_then(|values| Sides::from_shorthand(&values)) {
+                    if declaration.name == "margin" {
+                        self.margin = sides;
+                    } else {
+                        self.padding = sides;
+                    }
+                }
+            }
+            "list-style" | "list-style-type" => {
+                if let Some(list_style) = keyword.split_whitespace().find_map(ListStyle::parse) {
+                    self.list_style = list_style;
+                }
+            }
+            "page-break-before" | "break-before" => self.break_before = is_page_break(&keyword),
+            "page-break-after" | "break-after" => self.break_after = is_page_break(&keyword),
+            "border" => {
+                if let Some(border) = parse_border(&keyword, font_size, root_font_size) {
+                    for side in self.border.all_mut() {
+                        *side = border;
+                    }
+                }
+            }
+            "border-width" | "border-style" | "border-color" => {
+                let property = &declaration.name["border-".len()..];
+                let sides = css::split_components(value);
+                if let Some(sides) = Sides::from_shorthand(&sides) {
+                    for (border, value) in self.border.all_mut().into_iter().zip([sides.top, sides.right, sides.bottom, sides.left]) {
+                        apply_border_property(border, property, value, font_size, root_font_size);
+                    }
+                }
+            }
+            name => {
+                if let Some(side) = name.strip_prefix("margin-") {
+                    if let (Some(target), Some(length)) = (self.margin.side_mut(side, direction), length(&keyword)) {
+                        *target = length;
+                    }
+                } else if let Some(side) = name.strip_prefix("padding-") {
+                    if let (Some(target), Some(length)) = (self.padding.side_mut(side, direction), length(&keyword)) {
+                        *target = length;
+                    }
+                } else if let Some(rest) = name.strip_prefix("border-") {
+                    // border-<side> or border-<side>-<width|style|color>
+                    let (side, property) = match rest.rsplit_once('-') {
+                        Some((side, property)) if matches!(property, "width" | "style" | "color") => (side, Some(property)),
+                        _ => (rest, None),
+                    };
+                    let Some(target) = self.border.side_mut(side, direction) else {
+                        return;
+                    };
+                    match property {
+                        Some(property) => apply_border_property(target, property, value, font_size, root_font_size),
+                        None => {
+                            if let Some(border) = parse_border(&keyword, font_size, root_font_size) {
+                                *target = border;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Parsed once and shared by every engine
+static USER_AGENT: LazyLock<Arc<Stylesheet>> = LazyLock::new(|| Arc::new(Stylesheet::parse(USER_AGENT_CSS)));
+
+/// Matches elements against the user agent stylesheet and any author
+/// stylesheets, and computes their styles.
+pub struct StyleEngine {
+    user_agent: Arc<Stylesheet>,
+    author: Vec<Arc<Stylesheet>>,
+    // Font size `rem` lengths are relative to
+    root_font_size: f32,
+    print_background: bool,
+}
+
+impl StyleEngine {
+    pub fn new(root_font_size: f32) -> Self {
+        Self {
+            user_agent: USER_AGENT.clone(),
+            author: Vec::new(),
+            root_font_size,
+            print_background: true,
+        }
+    }
+
+    /// Drops every background colour, as browsers do when printing without backgrounds.
+    pub fn set_print_background(&mut self, enable: bool) {
+        self.print_background = enable;
+    }
+
+    /// Author stylesheets added later win over earlier ones at equal specificity.
+    pub fn add_stylesheet<S: Into<Arc<Stylesheet>>>(&mut self, sheet: S) {
+        self.author.push(sheet.into());
+    }
+
+    pub fn compute(&self, element: &Handle, parent: &ComputedStyle) -> ComputedStyle {
+        // (important, origin, specificity, order)
+        let mut matched: Vec<((bool, u8, u32, usize), Declaration)> = Vec::new();
+        let mut order = 0;
+        let sheets = std::iter::once((0u8, &self.user_agent)).chain(self.author.iter().map(|sheet| (1u8, sheet)));
+        for (origin, sheet) in sheets {
+            for rule in &sheet.rules {
+                order += 1;
+                let specificity = rule
+                    .selectors
+                    .iter()
+                    .filter(|selector| selector.matches(element))
+                    .map(|selector| selector.specificity())
+                    .max();
+                if let Some(specificity) = specificity {
+                    for declaration in &rule.declarations {
+                        matched.push(((declaration.important, origin, specificity, order), declaration.clone()));
+                    }
+                }
+            }
+        }
+        // The `dir` attribute maps to `direction` below any stylesheet rule
+        if let Some(dir) = css::attribute(element, "dir") {
+            let declaration = Declaration {
+                name: "direction".to_string(),
+                value: dir,
+                important: false,
+            };
+            matched.push(((false, 1, 0, 0), declaration));
+        }
+        if let Some(style) = css::attribute(element, "style") {
+            for declaration in css::parse_declarations(&style) {
+                matched.push(((declaration.important, 2, 0, 0), declaration));
+            }
+        }
+        matched.sort_by_key(|(priority, _)| *priority);
+
+        let mut style = ComputedStyle::inherit(parent);
+        // Lengths depend on the font size and logical sides on the direction
+        let (first, rest): (Vec<_>, Vec<_>) = matched
+            .iter()
+            .map(|(_, declaration)| declaration)
+            .partition(|declaration| matches!(declaration.name.as_str(), "font-size" | "direction"));
+        for declaration in first.into_iter().chain(rest) {
+            style.apply(declaration, parent, self.root_font_size);
+        }
+        if !self.print_background {
+            style.background_color = None;
+            style.background_image = None;
+        }
+
+        // Inline backgrounds are painted behind each piece of text, block
+        // backgrounds behind the whole box
+        style.text.background_color = match style.display {
+            Display::Inline => style.background_color.or(parent.text.background_color),
+            _ => None,
+        };
+        // Inline content with a border, such as `kbd` keycaps, is boxed on each line
+        style.text.border = match style.display {
+            Display::Inline => {
+                let border = &style.border;
+                [border.top, border.right, border.bottom, border.left]
+                    .into_iter()
+                    .find(|side| side.used_width() > 0.0)
+                    .or(parent.text.border)
+            }
+            _ => None,
+        };
+        style
+    }
+}
+
+fn px(value: f32) -> f32 {
+    value * 25.4 / 96.0
+}
+
+// CSS length in mm; `em` is relative to `font_size` and `rem` to `root_font_size` (both pt)
+fn length(value: &str, font_size: f32, root_font_size: f32) -> Option<f32> {
+    let value = value.trim();
+    if let Some(number) = value.strip_suffix("rem") {
+        return number.parse::<f32>().ok().map(|n| pt_to_mm(n * root_font_size));
+    }
+    if let Some(number) = value.strip_suffix("em") {
+        return number.parse::<f32>().ok().map(|n| pt_to_mm(n * font_size));
+    }
+    match value {
+        "auto" => Some(0.0),
+        "thin" => Some(px(1.0)),
+        "medium" => Some(px(3.0)),
+        "thick" => Some(px(5.0)),
+        _ => parse_length(value).ok(),
+    }
+}
+
+fn parse_font_size(value: &str, parent_size: f32, root_size: f32) -> Option<f32> {
+    let keyword_scale = match value {
+        "xx-small" => Some(0.6),
+        "x-small" => Some(0.75),
+        "small" => Some(0.89),
+        "medium" => Some(1.0),
+        "large" => Some(1.2),
+        "x-large" => Some(1.5),
+        "xx-large" => Some(2.0),
+        "xxx-large" => Some(3.0),
+        _ => None,
+    };
+    if let Some(scale) = keyword_scale {
+        return Some(root_size * scale);
+    }
+    match value {
+        "smaller" => Some(parent_size / 1.2),
+        "larger" => Some(parent_size * 1.2),
+        _ => match value.strip_suffix('%') {
+            Some(percent) => percent.parse::<f32>().ok().map(|p| parent_size * p / 100.0),
+            None => length(value, parent_size, root_size).map(|mm| mm * 72.0 / 25.4),
+        },
+    }
+}
+
+fn parse_display(value: &str) -> Option<Display> {
+    Some(match value {
+        "none" => Display::None,
+        "inline" | "inline-block" | "inline-flex" | "contents" => Display::Inline,
+        "list-item" => Display::ListItem,
+        "table-row" => Display::TableRow,
+        "table-cell" => Display::TableCell,
+        "block" | "flex" | "grid" | "flow-root" | "table" | "inline-table" | "table-row-group"
+        | "table-header-group" | "table-footer-group" | "table-caption" => Display::Block,
+        _ => return None,
+    })
+}
+
+fn is_page_break(value: &str) -> bool {
+    matches!(value, "always" | "page" | "left" | "right")
+}
+
+fn apply_border_property(border: &mut Border, property: &str, value: &str, font_size: f32, root_font_size: f32) {
+    match property {
+        "width" => {
+            if let Some(width) = length(value, font_size, root_font_size) {
+                border.width = width;
+            }
+        }
+        "style" => border.visible = !matches!(value.to_ascii_lowercase().as_str(), "none" | "hidden"),
+        "color" => border.color = parse_color(value),
+        _ => {}
+    }
+}
+
+// `border` shorthands such as `1px solid #dcdcdc`; omitted parts reset to their initial values
+fn parse_border(value: &str, font_size: f32, root_font_size: f32) -> Option<Border> {
+    let mut border = Border::default();
+    for part in css::split_components(value) {
+        if matches!(part, "none" | "hidden") {
+            border.visible = false;
+        } else if matches!(part, "solid" | "dashed" | "dotted" | "double" | "groove" | "ridge" | "inset" | "outset") {
+            border.visible = true;
+        } else if let Some(width) = length(part, font_size, root_font_size) {
+            border.width = width;
+        } else if let Some(color) = parse_color(part) {
+            border.color = Some(color);
+        } else {
+            return None;
+        }
+    }
+    Some(border)
+}
+
+pub fn parse_direction(value: &str) -> Option<TextDirection> {
+    match value.trim() {
+        "ltr" => Some(TextDirection::Ltr),
+        "rtl" => Some(TextDirection::Rtl),
+        "auto" => Some(TextDirection::Auto),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html5ever::parse_document;
+    use html5ever::tendril::TendrilSink;
+    use markup5ever_rcdom::{NodeData, RcDom};
+
+    fn first(node: &Handle, tag: &str) -> Option<Handle> {
+        if let NodeData::Element { ref name, .. } = node.data {
+            if name.local.as_ref() == tag {
+                return Some(node.clone());
+            }
+        }
+        node.children.borrow().iter().find_map(|child| first(child, tag))
+    }
+
+    #[test]
+    fn test_cascade_and_inheritance() {
+        let html = "<div class=\"note\" style=\"color: #ff0000\"><h1>Title <code>x</code></h1></div>";
+        let dom = parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(&mut html.as_bytes())
+            .unwrap();
+        let mut engine = StyleEngine::new(10.0);
+        engine.add_stylesheet(Stylesheet::parse(
+            ".note { color: blue; padding: 2mm 4mm; border-left: 4px solid #dcdcdc } h1 { margin: 0 } code { background-color: #f8f8f8; font-size: 0.5em }",
+        ));
+
+        let root = ComputedStyle::root(TextFormat { font_size: 10.0, ..TextFormat::default() });
+        let div = engine.compute(&first(&dom.document, "div").unwrap(), &root);
+        assert_eq!(div.display, Display::Block);
+        // The style attribute wins over the class rule
+        assert_eq!(div.text.color, (1.0, 0.0, 0.0, 1.0));
+        assert_eq!(div.padding.left, 4.0);
+        assert_eq!(div.border.left.used_width(), px(4.0));
+        assert_eq!(div.border.right.used_width(), 0.0);
+
+        let h1 = engine.compute(&first(&dom.document, "h1").unwrap(), &div);
+        assert_eq!(h1.text.font_size, 20.0);
+        assert!(h1.text.is_bold);
+        assert_eq!(h1.text.color, (1.0, 0.0, 0.0, 1.0));
+        assert_eq!(h1.margin.top, 0.0);
+
+        let code = engine.compute(&first(&dom.document, "code").unwrap(), &h1);
+        assert_eq!(code.text.font_size, 10.0);
+        assert_eq!(code.text.font_family.as_deref(), Some("monospace"));
+        assert!(code.text.background_color.is_some());
+    }
+
+    #[test]
+    fn test_list_markers() {
+        assert_eq!(ListStyle::Decimal.marker(3).unwrap(), "3.");
+        assert_eq!(ListStyle::LowerAlpha.marker(28).unwrap(), "ab.");
+        assert_eq!(ListStyle::UpperRoman.marker(14).unwrap(), "XIV.");
+        assert!(ListStyle::None.marker(1).is_none());
+    }
+
+    #[test]
+    fn test_inline_decorations_and_shifts() {
+        let html = "<p><a href=\"#\"><del>gone</del></a> x<sup>2</sup> <kbd>Ctrl</kbd></p>";
+        let dom = parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(&mut html.as_bytes())
+            .unwrap();
+        let engine = StyleEngine::new(12.0);
+        let root = ComputedStyle::root(TextFormat { font_size: 12.0, ..TextFormat::default() });
+        let p = engine.compute(&first(&dom.document, "p").unwrap(), &root);
+
+        let a = engine.compute(&first(&dom.document, "a").unwrap(), &p);
+        let del = engine.compute(&first(&dom.document, "del").unwrap(), &a);
+        assert!(del.text.is_strikethrough && del.text.is_underline);
+
+        let sup = engine.compute(&first(&dom.document, "sup").unwrap(), &p);
+        assert_eq!(sup.text.font_size, 10.0);
+        assert!((sup.text.baseline_shift - 3.96).abs() < 1e-4);
+
+        let kbd = engine.compute(&first(&dom.document, "kbd").unwrap(), &p);
+        assert_eq!(kbd.text.border.map(|border| border.width), Some(px(1.0)));
+        assert!(p.text.border.is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::{Mark2PdfError, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Expands `{{ name }}` placeholders in a document body. Values can be piped
+/// through filters, as in `{{ date | format("%d %B %Y") }}`, `{{ title | upper }}`
+/// or `{{ status | default("final") }}`. Quoted strings are literals, so
+/// `{{ "{{" }}` writes a literal pair of braces. `date` is today's date unless
+/// a variable of that name is set. Placeholders naming undefined variables,
+/// and a `{{` that is never closed, are left as written with a warning, so
+/// prose quoting other template languages still converts.
+pub fn expand(text: &str, variables: &BTreeMap<String, String>) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = find_unquoted(after, "}}") else {
+            log::warn!("Leaving '{{{{' without a closing '}}}}' as written");
+            rest = &rest[start..];
+            break;
+        };
+        let placeholder = &rest[start..start + end + 4];
+        match evaluate(&after[..end], variables)? {
+            Some(value) => output.push_str(&value),
+            None => {
+                log::warn!("Leaving '{}' as written: the variable is not defined", placeholder);
+                output.push_str(placeholder);
+            }
+        }
+        rest = &after[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+// The placeholder's value, or None when it names an undefined variable
+fn evaluate(expression: &str, variables: &BTreeMap<String, String>) -> Result<Option<String>> {
+    let mut parts = split_unquoted(expression, '|').into_iter();
+    let name = parts.next().unwrap_or_default().trim();
+    let mut value = match literal(name) {
+        Some(text) => Some(text.to_string()),
+        None => variables
+            .get(name)
+            .cloned()
+            .or_else(|| (name == "date").then(|| Local::now().format("%Y-%m-%d").to_string())),
+    };
+
+    for filter in parts {
+        let filter = filter.trim();
+        let (filter_name, args) = match filter.split_once('(') {
+            Some((filter_name, args)) => {
+                let args = args
+                    .strip_suffix(')')
+                    .ok_or_else(|| template_error(format!("missing ')' in filter '{}'", filter)))?;
+                let args = split_unquoted(args, ',')
+                    .into_iter()
+                    .map(|arg| literal(arg.trim()).ok_or_else(|| template_error(format!("filter arguments must be quoted in '{}'", filter))))
+                    .collect::<Result<Vec<_>>>()?;
+                (filter_name.trim(), args)
+            }
+            None => (filter, Vec::new()),
+        };
+        if filter_name == "default" {
+            let fallback = args.first().copied().unwrap_or_default();
+            value = value.filter(|value| !value.is_empty()).or_else(|| Some(fallback.to_string()));
+            continue;
+        }
+        let Some(current) = value else {
+            return Ok(None);
+        };
+        value = Some(match (filter_name, args.as_slice()) {
+            ("upper", []) => current.to_uppercase(),
+            ("lower", []) => current.to_lowercase(),
+            ("format", [format]) => format_date(&current, format)?,
+            _ => return Err(template_error(format!("unknown filter '{}'", filter))),
+        });
+    }
+    Ok(value)
+}
+
+// Dates are read as RFC 3339 timestamps or ISO dates, with or without a time
+fn format_date(value: &str, format: &str) -> Result<String> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(template_error(format!("invalid date format '{}'", format)));
+    }
+    let date: DateTime<FixedOffset> = DateTime::parse_from_rfc3339(value)
+        .ok()
+        .or_else(|| {
+            ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"]
+                .iter()
+                .find_map(|pattern| NaiveDateTime::parse_from_str(value, pattern).ok())
+                .or_else(|| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+                .map(|date| date.and_utc().fixed_offset())
+        })
+        .ok_or_else(|| template_error(format!("'{}' is not a date", value)))?;
+    let mut output = String::new();
+    write!(output, "{}", date.format_with_items(items.into_iter()))
+        .map_err(|_| template_error(format!("cannot format '{}' with '{}'", value, format)))?;
+    Ok(output)
+}
+
+// The text between matching single or double quotes
+fn literal(text: &str) -> Option<&str> {
+    let quote = text.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    text.strip_prefix(quote)?.strip_suffix(quote)
+}
+
+fn find_unquoted(text: &str, pattern: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if matches!(c, '"' | '\'') => quote = Some(c),
+            None if text[index..].starts_with(pattern) => return Some(index),
+            None => {}
+        }
+    }
+    None
+}
+
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = text;
+    while let Some(index) = find_unquoted(rest, separator.encode_utf8(&mut [0; 4])) {
+        parts.push(&rest[..index]);
+        rest = &rest[index + separator.len_utf8()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+fn template_error(message: String) -> Mark2PdfError {
+    Mark2PdfError::MarkdownError(format!("template: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("version".to_string(), "1.4.2".to_string()),
+            ("released".to_string(), "2024-03-05".to_string()),
+            ("client".to_string(), "Acme".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_expand_variables_and_filters() {
+        let text = "v{{version}} on {{ released | format(\"%d %B %Y\") }} for {{ client | upper }}, {{ tier | default('basic') }}";
+        assert_eq!(expand(text, &variables()).unwrap(), "v1.4.2 on 05 March 2024 for ACME, basic");
+        assert_eq!(expand("{{ \"{{\" }} x }}", &variables()).unwrap(), "{{ x }}");
+        let today = expand("{{ date | format('%Y') }}", &variables()).unwrap();
+        assert_eq!(today, Local::now().format("%Y").to_string());
+    }
+
+    #[test]
+    fn test_template_errors() {
+        for text in ["{{ client | shout }}", "{{ client | format('%Y') }}", "{{ released | format('%Q') }}"] {
+            assert!(matches!(expand(text, &variables()), Err(Mark2PdfError::MarkdownError(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_undefined_placeholders_are_left_as_written() {
+        let text = "Vue shows {{ message | capitalize }}, Jinja {{user.name}} and {{ version }}; {{ unclosed";
+        assert_eq!(expand(text, &variables()).unwrap(), "Vue shows {{ message | capitalize }}, Jinja {{user.name}} and 1.4.2; {{ unclosed");
+    }
+}
@@ -0,0 +1,314 @@
+use crate::error::{Mark2PdfError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Bundled looks applied on top of the built-in stylesheet. `Default` adds
+/// nothing to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NamedTheme {
+    #[default]
+    Default,
+    Github,
+    // Serif body text and booktabs-style tables
+    Academic,
+    Minimal,
+    Corporate,
+    Dark,
+}
+
+impl NamedTheme {
+    pub const ALL: [NamedTheme; 6] = [
+        NamedTheme::Default,
+        NamedTheme::Github,
+        NamedTheme::Academic,
+        NamedTheme::Minimal,
+        NamedTheme::Corporate,
+        NamedTheme::Dark,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NamedTheme::Default => "default",
+            NamedTheme::Github => "github",
+            NamedTheme::Academic => "academic",
+            NamedTheme::Minimal => "minimal",
+            NamedTheme::Corporate => "corporate",
+            NamedTheme::Dark => "dark",
+        }
+    }
+
+    pub fn stylesheet(&self) -> &'static str {
+        match self {
+            NamedTheme::Default => "",
+            NamedTheme::Github => include_str!("themes/github.css"),
+            NamedTheme::Academic => include_str!("themes/academic.css"),
+            NamedTheme::Minimal => include_str!("themes/minimal.css"),
+            NamedTheme::Corporate => include_str!("themes/corporate.css"),
+            NamedTheme::Dark => include_str!("themes/dark.css"),
+        }
+    }
+}
+
+impl FromStr for NamedTheme {
+    type Err = Mark2PdfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s.trim().to_ascii_lowercase();
+        NamedTheme::ALL.into_iter().find(|theme| theme.name() == name).ok_or_else(|| {
+            let names: Vec<&str> = NamedTheme::ALL.iter().map(NamedTheme::name).collect();
+            Mark2PdfError::ConfigError(format!("unknown theme '{}' (expected one of {})", s.trim(), names.join(", ")))
+        })
+    }
+}
+
+/// Typed settings for the built-in stylesheet. Colours are CSS colour
+/// values and sizes are in em of the body font size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Theme {
+    pub body: BodyStyle,
+    // h1 to h6
+    pub headings: [HeadingStyle; 6],
+    pub link_color: String,
+    pub code: CodeStyle,
+    pub table: TableStyle,
+    pub blockquote: BlockquoteStyle,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BodyStyle {
+    pub font_family: String,
+    pub color: String,
+    pub line_height: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadingStyle {
+    pub font_size: f32,
+    pub font_weight: u16,
+    // Inherits the body colour when unset
+    pub color: Option<String>,
+    pub space_before: f32,
+    pub space_after: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CodeStyle {
+    pub font_family: String,
+    pub font_size: f32,
+    pub color: Option<String>,
+    pub background: String,
+    // Background of fenced and indented code blocks
+    pub block_background: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TableStyle {
+    pub border_color: String,
+    pub cell_padding: f32,
+    pub header_background: String,
+    pub header_font_weight: u16,
+    // Background of every second body row; rows are not striped when unset
+    pub stripe_background: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BlockquoteStyle {
+    pub color: String,
+    pub border_color: String,
+    // CSS length of the bar on the side where lines start
+    pub border_width: String,
+    pub italic: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let heading = |font_size| HeadingStyle {
+            font_size,
+            ..HeadingStyle::default()
+        };
+        Self {
+            body: BodyStyle::default(),
+            headings: [heading(2.0), heading(1.5), heading(1.17), heading(1.0), heading(0.83), heading(0.67)],
+            link_color: "#3498db".to_string(),
+            code: CodeStyle::default(),
+            table: TableStyle::default(),
+            blockquote: BlockquoteStyle::default(),
+        }
+    }
+}
+
+impl Default for BodyStyle {
+    fn default() -> Self {
+        Self {
+            font_family: "system-ui, -apple-system, 'Segoe UI', 'Roboto', 'Helvetica Neue', sans-serif".to_string(),
+            color: "#111".to_string(),
+            line_height: 1.6,
+        }
+    }
+}
+
+impl Default for HeadingStyle {
+    fn default() -> Self {
+        Self {
+            font_size: 1.0,
+            font_weight: 700,
+            color: None,
+            space_before: 0.5,
+            space_after: 0.25,
+        }
+    }
+}
+
+impl Default for CodeStyle {
+    fn default() -> Self {
+        Self {
+            font_family: "'SFMono-Regular', Consolas, 'Liberation Mono', Menlo, monospace".to_string(),
+            font_size: 0.9,
+            color: None,
+            background: "#f8f8f8".to_string(),
+            block_background: "#f8f8f8".to_string(),
+        }
+    }
+}
+
+impl Default for TableStyle {
+    fn default() -> Self {
+        Self {
+            border_color: "#dcdcdc".to_string(),
+            cell_padding: 0.5,
+            header_background: "#f8f8f8".to_string(),
+            header_font_weight: 600,
+            stripe_background: Some("#f8f8f8".to_string()),
+        }
+    }
+}
+
+impl Default for BlockquoteStyle {
+    fn default() -> Self {
+        Self {
+            color: "#666".to_string(),
+            border_color: "#dcdcdc".to_string(),
+            border_width: "4px".to_string(),
+            italic: false,
+        }
+    }
+}
+
+impl Theme {
+    /// The built-in stylesheet with this theme's values, for a body font
+    /// size in points.
+    pub fn to_css(&self, font_size: f32) -> String {
+        let Theme { body, headings, code, table, blockquote, .. } = self;
+        let mut css = String::new();
+        css.push_str("* { box-sizing: border-box; }\n");
+        let _ = writeln!(
+            css,
+            "body {{ font-family: {}; line-height: {}; font-size: {}pt; color: {}; margin: 0; padding: 1em; }}",
+            body.font_family, body.line_height, font_size, body.color
+        );
+        css.push_str("h1, h2, h3, h4, h5, h6 { margin: 0; line-height: 1.2; }\n");
+        for (level, heading) in headings.iter().enumerate() {
+            let _ = write!(
+                css,
+                "h{} {{ font-size: {}em; font-weight: {}; padding: {}em 0 {}em; ",
+                level + 1,
+                heading.font_size,
+                heading.font_weight,
+                heading.space_before,
+                heading.space_after
+            );
+            if let Some(color) = &heading.color {
+                let _ = write!(css, "color: {}; ", color);
+            }
+            css.push_str("}\n");
+        }
+        css.push_str("p { margin: 0 0 0.75em; }\n");
+        let _ = writeln!(
+            css,
+            "blockquote {{ margin: 0.5em 0; padding-inline-start: 1em; border-inline-start: {} solid {}; color: {};{} }}",
+            blockquote.border_width,
+            blockquote.border_color,
+            blockquote.color,
+            if blockquote.italic { " font-style: italic;" } else { "" }
+        );
+        css.push_str("ul, ol { margin: 0 0 0.75em; padding-inline-start: 2em; }\n");
+        let _ = writeln!(css, "a {{ color: {}; }}", self.link_color);
+        css.push_str("li { margin: 0.25em 0; }\n");
+        let _ = writeln!(
+            css,
+            "pre {{ margin: 0.75em 0; padding: 1em; background-color: {}; border-radius: 4px; overflow-x: auto; }}",
+            code.block_background
+        );
+        let _ = writeln!(
+            css,
+            "code {{ background-color: {}; padding: 0.2em 0.4em; border-radius: 3px; font-family: {}; font-size: {}em;{} }}",
+            code.background,
+            code.font_family,
+            code.font_size,
+            code.color.as_ref().map(|color| format!(" color: {};", color)).unwrap_or_default()
+        );
+        css.push_str("pre code { padding: 0; background: none; }\n");
+        css.push_str("img { max-width: 100%; height: auto; margin: 0.75em 0; }\n");
+        css.push_str("table { border-spacing: 0; border-collapse: collapse; margin: 0.75em 0; width: 100%; }\n");
+        let _ = writeln!(
+            css,
+            "table th, table td {{ padding: {}em; border: 1px solid {}; }}",
+            table.cell_padding, table.border_color
+        );
+        let _ = writeln!(
+            css,
+            "table th {{ font-weight: {}; background-color: {}; }}",
+            table.header_font_weight, table.header_background
+        );
+        if let Some(stripe) = &table.stripe_background {
+            let _ = writeln!(css, "table tr:nth-child(2n) {{ background-color: {}; }}", stripe);
+        }
+        let _ = writeln!(css, "hr {{ margin: 1.5em 0; border: 0; border-top: 1px solid {}; }}", table.border_color);
+        css.push_str(".page-break { page-break-after: always; }\n");
+        css
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::css::Stylesheet;
+
+    #[test]
+    fn test_bundled_themes_parse() {
+        for theme in NamedTheme::ALL {
+            assert_eq!(theme.name().parse::<NamedTheme>().unwrap(), theme);
+            let css = theme.stylesheet();
+            let rules = Stylesheet::parse(css).rules.len();
+            assert_eq!(rules, css.matches('}').count(), "{} has a rule the parser drops", theme.name());
+        }
+        assert!("GitHub".parse::<NamedTheme>().is_ok());
+        assert!(matches!("solarized".parse::<NamedTheme>(), Err(Mark2PdfError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_typed_theme_round_trips_and_renders() {
+        let mut theme = Theme::default();
+        theme.headings[1].color = Some("#003366".to_string());
+        theme.table.stripe_background = None;
+        let json = serde_json::to_string(&theme).unwrap();
+        assert_eq!(serde_json::from_str::<Theme>(&json).unwrap(), theme);
+
+        let css = theme.to_css(12.0);
+        assert!(css.contains("font-size: 12pt"));
+        assert!(css.contains("h2 { font-size: 1.5em; font-weight: 700; padding: 0.5em 0 0.25em; color: #003366; }"));
+        assert!(!css.contains("nth-child"));
+        // Partial documents fill the rest from the defaults
+        let partial: Theme = serde_json::from_str(r##"{"link_color": "#ff0000"}"##).unwrap();
+        assert_eq!(partial.headings, Theme::default().headings);
+        assert_eq!(Stylesheet::parse(&css).rules.len(), css.matches('}').count());
+    }
+}
@@ -17,6 +17,24 @@ pub enum Mark2PdfError {
 
     #[error("Image error: {0}")]
     ImageError(String),
+
+    #[error("Font error: {0}")]
+    FontError(String),
+}
+
+impl Mark2PdfError {
+    /// The process exit code the command line tool uses for this error.
+    /// 1 is left for unexpected failures and 2 for invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Mark2PdfError::IoError(_) => 3,
+            Mark2PdfError::ConfigError(_) => 4,
+            Mark2PdfError::MarkdownError(_) => 5,
+            Mark2PdfError::ImageError(_) => 6,
+            Mark2PdfError::FontError(_) => 7,
+            Mark2PdfError::PdfError(_) => 8,
+        }
+    }
 }
 
 impl From<printpdf::Error> for Mark2PdfError {
@@ -1,28 +1,131 @@
-use std::path::Path;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use crate::config::Config;
+use crate::config_file::ConfigFiles;
 use crate::core::markdown::MarkdownProcessor;
-use crate::core::pdf::html_to_pdf;
+use crate::core::pdf::{layout_document, PdfState};
+use crate::core::resources::Resources;
 use error::Result;
 
 pub mod config;
+pub mod config_env;
+pub mod config_file;
 pub mod core;
 pub mod error;
 
 pub struct Mark2Pdf {
     markdown_processor: MarkdownProcessor,
+    config: Config,
+    // Kept between conversions, so converting again only reloads what changed
+    resources: Mutex<Resources>,
 }
 
 impl Mark2Pdf {
     pub fn new() -> Self {
         Self {
             markdown_processor: MarkdownProcessor::new(),
+            config: Config::default(),
+            resources: Mutex::new(Resources::new()),
         }
     }
 
+    pub fn with_config(config: Config) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            markdown_processor: MarkdownProcessor::from_config(&config),
+            config,
+            resources: Mutex::new(Resources::new()),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Converts a Markdown file, writing the PDF to `output_path`.
     pub fn convert<P: AsRef<Path>>(&self, input_path: P, output_path: P) -> Result<()> {
-        let content = std::fs::read_to_string(input_path).map_err(|e| error::Mark2PdfError::IoError(e))?;
-        let html = self.markdown_processor.process_content(&content)?;
-        html_to_pdf(&html, output_path.as_ref())?;
-        Ok(())
+        self.layout_file(input_path.as_ref())?.save_to_file(output_path)
+    }
+
+    /// Converts a Markdown file, writing the PDF to `writer`.
+    pub fn convert_file_to_writer<P: AsRef<Path>, W: Write>(&self, input_path: P, writer: W) -> Result<()> {
+        self.layout_file(input_path.as_ref())?.save_to(writer)
+    }
+
+    /// Converts Markdown text to the bytes of a PDF. Config files and
+    /// relative paths are looked up from the current directory.
+    pub fn convert_str(&self, markdown: &str) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.convert_to_writer(markdown, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Like [`Mark2Pdf::convert_str`], writing the PDF to `writer`.
+    pub fn convert_to_writer<W: Write>(&self, markdown: &str, writer: W) -> Result<()> {
+        let mut resources = self.resources();
+        resources.begin();
+        self.layout(markdown, Path::new("."), &mut resources)?.save_to(writer)
+    }
+
+    /// The files the last conversion read: the input, config files,
+    /// stylesheets, fonts and images. Files it could not read are included.
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        self.resources().dependencies().map(Path::to_path_buf).collect()
+    }
+
+    fn resources(&self) -> MutexGuard<'_, Resources> {
+        // The cache is only ever missing entries after a panic
+        self.resources.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Relative paths in the document, such as stylesheets and images, are relative to its directory
+    fn layout_file(&self, input_path: &Path) -> Result<PdfState> {
+        let mut resources = self.resources();
+        resources.begin();
+        resources.add_dependency(input_path);
+        let content = fs::read_to_string(input_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input_path.display(), e)))?;
+        let base_dir = input_path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        self.layout(&content, base_dir, &mut resources)
+    }
+
+    // Config files in `base_dir`, the environment when enabled and the front matter fill in
+    // the settings this converter's configuration was not given
+    fn layout(&self, markdown: &str, base_dir: &Path, resources: &mut Resources) -> Result<PdfState> {
+        let base = self.base_config(base_dir, config_env::vars(), resources)?;
+        let (html, config) = self.markdown_processor.process_document_with_resources(markdown, Some(base_dir), &base, resources)?;
+        layout_document(&html, &config, resources)
+    }
+
+    // The config files and, when enabled, the `MARK2PDF_*` variables in `vars`
+    fn base_config<I, K, V>(&self, base_dir: &Path, vars: I, resources: &mut Resources) -> Result<Config>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let (environment, config_files) = match self.config.environment {
+            true => config_env::overlay(vars)?,
+            false => (serde_json::Value::Object(Default::default()), None),
+        };
+        let files = match self.config.config_files && config_files != Some(false) {
+            true => ConfigFiles::discover(base_dir)?,
+            false => ConfigFiles::default(),
+        };
+        for path in &files.paths {
+            resources.add_dependency(path);
+        }
+        let mut base = files.load()?;
+        base.merge_value(environment)?;
+        Ok(base)
+    }
+}
+
+impl Default for Mark2Pdf {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -34,8 +137,6 @@ pub fn convert_markdown_to_pdf<P: AsRef<Path>>(input_path: P, output_path: P) ->
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
-    use std::io::Write;
     use tempfile::NamedTempFile;
 
     #[test]
@@ -45,9 +146,87 @@ mod tests {
         input_file.write_all(markdown_content.as_bytes()).unwrap();
         let output_file = NamedTempFile::new().unwrap();
 
-        convert_markdown_to_pdf(input_file.path(), output_file.path())?;
+        // Config files on this machine must not affect the tests
+        let converter = Mark2Pdf::with_config(Config::new().with_config_files(false))?;
+        converter.convert(input_file.path(), output_file.path())?;
 
         assert!(output_file.path().exists());
         Ok(())
     }
+
+    #[test]
+    fn test_convert_str_returns_pdf_bytes() {
+        let converter = Mark2Pdf::with_config(Config::new().with_config_files(false)).unwrap();
+        let bytes = converter.convert_str("---\ntitle: In memory\n---\n# {{ title }}\n").unwrap();
+        assert!(bytes.starts_with(b"%PDF-"));
+        let pdf = printpdf::lopdf::Document::load_mem(&bytes).unwrap();
+        assert_eq!(pdf.get_pages().len(), 1);
+
+        let mut written = Vec::new();
+        converter.convert_to_writer("# Second", &mut written).unwrap();
+        assert!(written.starts_with(b"%PDF-"));
+    }
+
+    #[test]
+    fn test_dependencies_of_last_conversion() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("doc.md");
+        let sheet = dir.path().join("print.css");
+        std::fs::write(&input, "---\nstylesheet: print.css\n---\n# Notes\n").unwrap();
+        std::fs::write(&sheet, "h1 { color: navy }").unwrap();
+        let converter = Mark2Pdf::with_config(Config::new().with_config_files(false)).unwrap();
+        converter.convert(&input, &dir.path().join("doc.pdf")).unwrap();
+        assert_eq!(converter.dependencies(), vec![input.clone(), sheet.clone()]);
+
+        std::fs::write(&input, "---\nstylesheet: missing.css\n---\n# Notes\n").unwrap();
+        assert!(converter.convert(&input, &dir.path().join("doc.pdf")).is_err());
+        assert_eq!(converter.dependencies(), vec![input, dir.path().join("missing.css")]);
+    }
+
+    #[test]
+    fn test_images_are_found_next_to_the_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("docs");
+        std::fs::create_dir(&docs).unwrap();
+        ::image::RgbImage::new(2, 2).save(docs.join("chart.png")).unwrap();
+        let input = docs.join("notes.md");
+        std::fs::write(&input, "# Notes\n\n![chart](chart.png)\n\n<img src=\"chart.png\">\n").unwrap();
+        let converter = Mark2Pdf::with_config(Config::new().with_config_files(false)).unwrap();
+        converter.convert(&input, &dir.path().join("notes.pdf")).unwrap();
+        assert_eq!(converter.dependencies(), vec![docs.join("chart.png"), input]);
+    }
+
+    #[test]
+    fn test_with_config_rejects_invalid_layout() {
+        let config = Config::new().with_page_size(100.0, 100.0).with_margin(60.0);
+        assert!(matches!(Mark2Pdf::with_config(config), Err(error::Mark2PdfError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_environment_is_only_read_when_enabled() {
+        let vars = [("MARK2PDF_FONT_SIZE", "14"), ("MARK2PDF_CONFIG_FILES", "false")];
+        let base_size = |config: Config| {
+            let converter = Mark2Pdf::with_config(config).unwrap();
+            converter.base_config(Path::new("."), vars, &mut Resources::new()).unwrap().font_size
+        };
+        let config = Config::new().with_config_files(false);
+        assert_eq!(base_size(config.clone()), 11.0);
+        assert_eq!(base_size(config.with_environment(true)), 14.0);
+    }
+
+    #[test]
+    fn test_front_matter_sets_page_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("doc.md");
+        std::fs::write(&input, "---\npage_format: A5\norientation: landscape\ntitle: Notes\n---\n# Notes\n").unwrap();
+        let output = dir.path().join("doc.pdf");
+        let converter = Mark2Pdf::with_config(Config::new().with_config_files(false)).unwrap();
+        converter.convert(&input, &output).unwrap();
+
+        let pdf = printpdf::lopdf::Document::load(&output).unwrap();
+        let page = pdf.get_dictionary(pdf.get_pages()[&1]).unwrap();
+        let media_box = page.get(b"MediaBox").unwrap().as_array().unwrap();
+        let width = media_box[2].as_float().unwrap();
+        assert!((width - 595.3).abs() < 1.0, "A5 landscape is 210mm wide, got {}pt", width);
+    }
 }
@@ -0,0 +1,461 @@
+use clap::Parser;
+use mark2pdf::config::{Config, Margins, Orientation, PageFormat, Watermark};
+use mark2pdf::core::theme::NamedTheme;
+use mark2pdf::error::{Mark2PdfError, Result};
+use mark2pdf::Mark2Pdf;
+use notify_debouncer_full::{new_debouncer, DebouncedEvent};
+use notify_debouncer_full::notify::RecursiveMode;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Convert a Markdown document to PDF.
+///
+/// Options given here override the document's front matter, `MARK2PDF_*`
+/// environment variables and mark2pdf.toml or .mark2pdfrc.json config files.
+#[derive(Parser, Debug)]
+#[command(name = "mark2pdf", version, about, long_about, after_help = EXIT_CODES)]
+struct Cli {
+    /// Markdown file to convert, or - to read standard input
+    input: PathBuf,
+
+    /// Where to write the PDF, or - for standard output [default: the input
+    /// with a .pdf extension, or standard output when reading standard input]
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Paper size: A3, A4, A5, Letter, Legal, Tabloid, or WIDTHxHEIGHT in mm
+    #[arg(short = 'p', long, alias = "page-format", value_name = "SIZE", value_parser = parse_page_size)]
+    page_size: Option<PageFormat>,
+
+    /// portrait or landscape; sizes given as WIDTHxHEIGHT are used as written
+    #[arg(long)]
+    orientation: Option<Orientation>,
+
+    /// Margins in mm, or one to four CSS lengths such as "1in 20mm"
+    #[arg(short, long, alias = "margins", value_parser = parse_margins)]
+    margin: Option<Margins>,
+
+    /// Body font size in points
+    #[arg(short = 's', long, value_name = "PT")]
+    font_size: Option<f32>,
+
+    /// Font family to look up among installed fonts
+    #[arg(long, value_name = "NAME")]
+    font_family: Option<String>,
+
+    /// Font to use for characters the main font lacks (repeatable)
+    #[arg(long, value_name = "PATH")]
+    fallback_font: Vec<PathBuf>,
+
+    /// Extra directory to search for fonts (repeatable)
+    #[arg(long, value_name = "DIR")]
+    font_dir: Vec<PathBuf>,
+
+    /// Colour emoji font
+    #[arg(long, value_name = "PATH")]
+    emoji_font: Option<PathBuf>,
+
+    /// Directory of emoji images named by code point
+    #[arg(long, value_name = "DIR")]
+    emoji_images: Option<PathBuf>,
+
+    /// Turn on GitHub Flavored Markdown extensions
+    #[arg(long, overrides_with = "no_gfm")]
+    gfm: bool,
+
+    /// Turn off GitHub Flavored Markdown extensions
+    #[arg(long)]
+    no_gfm: bool,
+
+    /// Accepted but does nothing: math is not rendered yet and $...$ stays plain text
+    #[arg(long, overrides_with = "no_math")]
+    math: bool,
+
+    #[arg(long, hide = true)]
+    no_math: bool,
+
+    #[arg(long, hide = true, overrides_with = "no_highlight")]
+    highlight: bool,
+
+    /// Accepted but does nothing: code blocks are not highlighted yet
+    #[arg(long)]
+    no_highlight: bool,
+
+    /// Built-in theme
+    #[arg(short, long)]
+    theme: Option<NamedTheme>,
+
+    /// CSS file applied after the theme (repeatable)
+    #[arg(long, value_name = "PATH")]
+    stylesheet: Vec<PathBuf>,
+
+    /// CSS applied after the stylesheets
+    #[arg(long, value_name = "CSS")]
+    css: Option<String>,
+
+    /// Page background colour
+    #[arg(long, value_name = "COLOR")]
+    page_background: Option<String>,
+
+    /// Image stretched over every page
+    #[arg(long, value_name = "PATH")]
+    page_background_image: Option<PathBuf>,
+
+    /// Draw element and page backgrounds
+    #[arg(long, overrides_with = "no_print_background")]
+    print_background: bool,
+
+    /// Leave out element and page backgrounds
+    #[arg(long)]
+    no_print_background: bool,
+
+    /// Text stamped diagonally across every page
+    #[arg(long, value_name = "TEXT")]
+    watermark: Option<String>,
+
+    /// Document title
+    #[arg(long)]
+    title: Option<String>,
+
+    /// Document author
+    #[arg(long)]
+    author: Option<String>,
+
+    /// Document subject
+    #[arg(long)]
+    subject: Option<String>,
+
+    /// Document keyword (repeatable)
+    #[arg(long)]
+    keyword: Vec<String>,
+
+    /// Running header; {page} and {pages} are replaced by page numbers
+    #[arg(long, value_name = "TEXT")]
+    header: Option<String>,
+
+    /// Running footer; {page} and {pages} are replaced by page numbers
+    #[arg(long, value_name = "TEXT")]
+    footer: Option<String>,
+
+    /// Template variable for {{ NAME }} placeholders (repeatable)
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_variable)]
+    variables: Vec<(String, String)>,
+
+    /// Ignore mark2pdf.toml and .mark2pdfrc.json files, and MARK2PDF_CONFIG_FILES
+    #[arg(long)]
+    no_config: bool,
+
+    /// Convert again whenever the input or a stylesheet, image, font or
+    /// config file it uses changes
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Log progress; repeat for more detail
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+// Editors write a file in several steps; wait for them to finish
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+const EXIT_CODES: &str = "Exit codes: 2 invalid arguments, 3 I/O error, 4 configuration error, 5 Markdown error, \
+                          6 image error, 7 font error, 8 PDF error";
+
+impl Cli {
+    // Only options that were given are set, so lower layers fill in the rest
+    fn config(&self) -> Config {
+        let mut config = Config::new().with_environment(true);
+        if let Some(format) = self.page_size {
+            config = config.with_page_format(format);
+        }
+        if let Some(orientation) = self.orientation {
+            config = config.with_orientation(orientation);
+        }
+        if let Some(margins) = self.margin {
+            config = config.with_margins(margins);
+        }
+        if let Some(size) = self.font_size {
+            config = config.with_font_size(size);
+        }
+        if let Some(family) = &self.font_family {
+            config = config.with_font_family(family);
+        }
+        for path in &self.fallback_font {
+            config = config.with_fallback_font(path);
+        }
+        for dir in &self.font_dir {
+            config = config.with_font_dir(dir);
+        }
+        if let Some(path) = &self.emoji_font {
+            config = config.with_emoji_font(path);
+        }
+        if let Some(dir) = &self.emoji_images {
+            config = config.with_emoji_images(dir);
+        }
+        if let Some(enable) = switch(self.gfm, self.no_gfm) {
+            config = config.with_gfm(enable);
+        }
+        if let Some(enable) = switch(self.math, self.no_math) {
+            config = config.with_math(enable);
+        }
+        if let Some(enable) = switch(self.highlight, self.no_highlight) {
+            config = config.with_syntax_highlighting(enable);
+        }
+        if let Some(enable) = switch(self.print_background, self.no_print_background) {
+            config = config.with_print_background(enable);
+        }
+        if self.no_config {
+            config = config.with_config_files(false);
+        }
+        if let Some(theme) = self.theme {
+            config = config.with_theme(theme);
+        }
+        for path in &self.stylesheet {
+            config = config.with_stylesheet(path);
+        }
+        if let Some(css) = &self.css {
+            config = config.with_custom_css(css);
+        }
+        if let Some(color) = &self.page_background {
+            config = config.with_page_background(color);
+        }
+        if let Some(path) = &self.page_background_image {
+            config = config.with_page_background_image(path);
+        }
+        if let Some(text) = &self.watermark {
+            config = config.with_watermark(Watermark::text(text));
+        }
+        if let Some(title) = &self.title {
+            config = config.with_title(title);
+        }
+        if let Some(author) = &self.author {
+            config = config.with_author(author);
+        }
+        if let Some(subject) = &self.subject {
+            config = config.with_subject(subject);
+        }
+        for keyword in &self.keyword {
+            config = config.with_keyword(keyword);
+        }
+        if let Some(header) = &self.header {
+            config = config.with_header(header);
+        }
+        if let Some(footer) = &self.footer {
+            config = config.with_footer(footer);
+        }
+        for (name, value) in &self.variables {
+            config = config.with_variable(name, value);
+        }
+        config
+    }
+
+    fn output(&self) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None if is_stdio(&self.input) => PathBuf::from(STDIO),
+            None => self.input.with_extension("pdf"),
+        }
+    }
+}
+
+// A `--name` and `--no-name` pair, of which clap keeps the last one given
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+// Names standard input or output in place of a path
+const STDIO: &str = "-";
+
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+fn parse_page_size(value: &str) -> Result<PageFormat> {
+    let Some((width, height)) = value.split_once(['x', 'X']) else {
+        return value.parse();
+    };
+    match (width.trim().parse(), height.trim().parse()) {
+        (Ok(width), Ok(height)) => Ok(PageFormat::Custom { width, height }),
+        _ => Err(Mark2PdfError::ConfigError(format!("page size '{}' must be WIDTHxHEIGHT in mm", value))),
+    }
+}
+
+fn parse_margins(value: &str) -> Result<Margins> {
+    match value.trim().parse::<f32>() {
+        Ok(mm) => Ok(Margins::uniform(mm)),
+        Err(_) => value.parse(),
+    }
+}
+
+fn parse_variable(value: &str) -> std::result::Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => Ok((name.trim().to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got '{}'", value)),
+    }
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    let output = cli.output();
+    let converter = Mark2Pdf::with_config(cli.config())?;
+    if cli.watch {
+        if is_stdio(&cli.input) || is_stdio(&output) {
+            return Err(Mark2PdfError::ConfigError("--watch needs input and output files rather than -".to_string()));
+        }
+        return watch(&converter, &cli.input, &output);
+    }
+    match (is_stdio(&cli.input), is_stdio(&output)) {
+        (false, false) => converter.convert(&cli.input, &output)?,
+        (false, true) => converter.convert_file_to_writer(&cli.input, io::stdout().lock())?,
+        (true, to_stdout) => {
+            let pdf = converter.convert_str(&io::read_to_string(io::stdin())?)?;
+            match to_stdout {
+                true => io::stdout().lock().write_all(&pdf)?,
+                false => fs::write(&output, pdf)?,
+            }
+        }
+    }
+    if !is_stdio(&output) {
+        log::info!("Wrote {}", output.display());
+    }
+    Ok(())
+}
+
+// Converts again after each change to a file the last conversion read.
+// Errors are printed and the previous PDF is kept, so a broken save in the
+// middle of an edit doesn't end the session.
+fn watch(converter: &Mark2Pdf, input: &Path, output: &Path) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut debouncer = new_debouncer(DEBOUNCE, None, sender).map_err(watch_error)?;
+    let mut watched_dirs = BTreeSet::new();
+    loop {
+        let start = Instant::now();
+        match converter.convert(input, output) {
+            Ok(()) => log::info!("Wrote {} in {:.0?}", output.display(), start.elapsed()),
+            Err(error) => eprintln!("mark2pdf: {}", error),
+        }
+
+        let dependencies: BTreeSet<PathBuf> = converter.dependencies().iter().map(|path| watch_path(path)).collect();
+        // Editors often save by replacing the file, so its directory is watched
+        let dirs: BTreeSet<PathBuf> = dependencies.iter().filter_map(|path| path.parent()).map(Path::to_path_buf).collect();
+        for dir in watched_dirs.difference(&dirs) {
+            let _ = debouncer.unwatch(dir);
+        }
+        watched_dirs.retain(|dir| dirs.contains(dir));
+        for dir in dirs {
+            if watched_dirs.contains(&dir) {
+                continue;
+            }
+            // Directories that don't exist yet are tried again after the next change
+            match debouncer.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    watched_dirs.insert(dir);
+                }
+                Err(error) => log::warn!("Cannot watch {}: {}", dir.display(), error),
+            }
+        }
+        log::info!("Watching {} files for changes", dependencies.len());
+
+        // Reading the files for the conversion shows up as access events
+        let changed = |event: &DebouncedEvent| {
+            !event.kind.is_access() && event.paths.iter().any(|path| dependencies.contains(&watch_path(path)))
+        };
+        loop {
+            match receiver.recv().map_err(watch_error)? {
+                Ok(events) if events.iter().any(changed) => break,
+                Ok(_) => {}
+                Err(errors) => errors.iter().for_each(|error| log::warn!("{}", error)),
+            }
+        }
+    }
+}
+
+// Watchers report paths with the directory resolved, which may differ from
+// how the document named them
+fn watch_path(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (path.parent().and_then(|dir| dir.canonicalize().ok()), path.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => path,
+    }
+}
+
+fn watch_error<E: std::error::Error + Send + Sync + 'static>(error: E) -> Mark2PdfError {
+    Mark2PdfError::IoError(io::Error::other(error))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    // Watching reports each conversion
+    let level = match (cli.verbose, cli.watch) {
+        (0, false) => "warn",
+        (0, true) | (1, _) => "info",
+        _ => "debug",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+
+    if let Err(error) = run(&cli) {
+        eprintln!("mark2pdf: {}", error);
+        process::exit(error.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arguments_map_onto_config() {
+        let cli = Cli::parse_from([
+            "mark2pdf", "notes.md", "-p", "200x300", "--margin", "1in 10mm", "--theme", "dark", "--no-gfm", "--math",
+            "--var", "client=Acme", "--no-config", "-vv",
+        ]);
+        let config = cli.config();
+        assert_eq!(cli.output(), PathBuf::from("notes.pdf"));
+        assert_eq!(config.page_format, PageFormat::Custom { width: 200.0, height: 300.0 });
+        assert_eq!(config.margins, Margins { top: 25.4, right: 10.0, bottom: 25.4, left: 10.0 });
+        assert_eq!(config.theme, NamedTheme::Dark);
+        assert!(!config.enable_gfm && config.enable_math && config.enable_syntax_highlighting);
+        assert!(switch(cli.print_background, cli.no_print_background).is_none());
+        assert_eq!(config.variables["client"], "Acme");
+        assert!(!config.config_files);
+        assert_eq!(cli.verbose, 2);
+        assert_eq!(Cli::parse_from(["mark2pdf", "-"]).output(), PathBuf::from("-"));
+        let cli = Cli::parse_from(["mark2pdf", "-", "--no-gfm", "--gfm", "--print-background", "--no-print-background"]);
+        assert_eq!((switch(cli.gfm, cli.no_gfm), switch(cli.print_background, cli.no_print_background)), (Some(true), Some(false)));
+
+        // Switches that are not given leave config files in charge
+        let mut base = Config::new();
+        base.merge_value(serde_json::json!({ "enable_gfm": false, "print_background": false })).unwrap();
+        base.merge(&Cli::parse_from(["mark2pdf", "-", "--print-background"]).config()).unwrap();
+        assert!(!base.enable_gfm && base.print_background);
+        assert_eq!(Cli::parse_from(["mark2pdf", "-", "-o", "out.pdf"]).output(), PathBuf::from("out.pdf"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch_paths_resolve_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        let resolved = dir.path().canonicalize().unwrap().join("doc.md");
+        assert_eq!(watch_path(&link.join("doc.md")), resolved);
+        assert_eq!(watch_path(&resolved), resolved);
+    }
+
+    #[test]
+    fn test_invalid_arguments_are_rejected() {
+        for args in [vec!["--theme", "neon"], vec!["--margin", "wide"], vec!["--var", "novalue"], vec!["-p", "10xten"]] {
+            let args = ["mark2pdf", "in.md"].into_iter().chain(args.iter().copied());
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+    }
+}